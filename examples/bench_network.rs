@@ -0,0 +1,55 @@
+extern crate learned_index_structures;
+
+use std::time::Instant;
+
+use learned_index_structures::neural::train;
+use learned_index_structures::neural::{Activation, Kernel, Network, Scratch};
+
+// Run a network shaped like the one `examples/config.toml` describes on the
+// same keys with each kernel the CPU supports, one key at a time and in
+// batches, and print the time per key.
+fn main() {
+    let widths = [32, 32, 32, 32];
+    let mut network = train::initial_network(&widths, Activation::LeakyRelu(0.3), 1);
+    let keys: Vec<f64> = (0..100000).map(|i| i as f64 / 1000.0).collect();
+
+    for &kernel in Kernel::ALL.iter().filter(|k| k.is_supported()) {
+        network.set_kernel(kernel);
+        println!(
+            "{:?}: {:.1} ns per key, {:.1} ns per key in batches",
+            kernel,
+            one_at_a_time(&network, &keys),
+            batched(&network, &keys)
+        );
+    }
+}
+
+fn nanos_per_key(keys: &[f64], start: Instant) -> f64 {
+    let elapsed = start.elapsed();
+    let nanos = elapsed.as_secs() as f64 * 1e9 + elapsed.subsec_nanos() as f64;
+    nanos / keys.len() as f64
+}
+
+fn one_at_a_time(network: &Network, keys: &[f64]) -> f64 {
+    let mut buf1 = vec![0.0; network.buf_size()];
+    let mut buf2 = vec![0.0; network.buf_size()];
+    let mut sum = 0.0;
+    let start = Instant::now();
+    for &x in keys.iter() {
+        sum += network.apply_buffer(x, &mut buf1, &mut buf2);
+    }
+    let result = nanos_per_key(keys, start);
+    // so the work isn't optimized away
+    assert!(sum.is_finite());
+    result
+}
+
+fn batched(network: &Network, keys: &[f64]) -> f64 {
+    let mut out = vec![0.0; keys.len()];
+    let mut scratch = Scratch::new();
+    let start = Instant::now();
+    network.apply_batch(keys, &mut out, &mut scratch);
+    let result = nanos_per_key(keys, start);
+    assert!(out.iter().all(|x| x.is_finite()));
+    result
+}
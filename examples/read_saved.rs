@@ -4,8 +4,10 @@ use std::env;
 use std::time::Duration;
 
 use learned_index_structures::bench;
+use learned_index_structures::bplustree::BPlusTree;
 use learned_index_structures::btree::BTree;
 use learned_index_structures::forwarding_model::{self, ForwardingModel};
+use learned_index_structures::node_search::{Linear, Simd};
 
 fn duration_to_secs(dur: Duration) -> f64 {
     let mut secs = dur.as_secs() as f64;
@@ -15,18 +17,57 @@ fn duration_to_secs(dur: Duration) -> f64 {
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let data = forwarding_model::read_data(&args[2]);
-    let model = ForwardingModel::read_toml(&args[1], &data);
+    let data: Box<[f32]> = forwarding_model::read_data(&args[2]);
+    let model: ForwardingModel = ForwardingModel::read_toml(&args[1], &data);
     println!(
         "Time for neural net model: {:.4}",
         duration_to_secs(bench::bench(&model, &data, 10000))
     );
-    let mut btree = BTree::new();
-    for i in 0..data.len() {
-        btree.insert(data[i], i as u32);
-    }
+    let indices: Vec<u32> = (0..data.len() as u32).collect();
+    bench_btree::<4>(&data, &indices);
+    bench_btree::<8>(&data, &indices);
+    bench_btree::<16>(&data, &indices);
+    bench_btree::<32>(&data, &indices);
+    bench_btree::<64>(&data, &indices);
+    bench_btree::<128>(&data, &indices);
+
+    let btree: BTree<f32, u32> = BTree::from_sorted_parts(&data, &indices);
+    println!(
+        "Time for B Tree with linear node search: {:.4}",
+        duration_to_secs(bench::bench(
+            &btree.with_node_search::<Linear>(),
+            &data,
+            10000
+        ))
+    );
+    println!(
+        "Time for B Tree with SIMD node search: {:.4}",
+        duration_to_secs(bench::bench(
+            &btree.with_node_search::<Simd>(),
+            &data,
+            10000
+        ))
+    );
+
+    let keys = data.iter().cloned();
+    let pairs: Vec<(f32, u32)> = keys.zip(indices.iter().cloned()).collect();
+    let bplustree: BPlusTree<f32, u32> = BPlusTree::from_sorted(&pairs);
+    println!(
+        "Time for B+ Tree: {:.4}",
+        duration_to_secs(bench::bench(&bplustree, &data, 10000))
+    );
+}
+
+fn bench_btree<const T: usize>(data: &[f32], indices: &[u32]) {
+    let btree: BTree<f32, u32, T> = BTree::from_sorted_parts(data, indices);
+    println!(
+        "Time for B Tree with minimum degree {}: {:.4}",
+        T,
+        duration_to_secs(bench::bench(&btree, data, 10000))
+    );
     println!(
-        "Time for B Tree: {:.4}",
-        duration_to_secs(bench::bench(&btree, &data, 10000))
+        "Stats for B Tree with minimum degree {}: {:?}",
+        T,
+        btree.stats()
     );
 }
@@ -1,6 +1,6 @@
 from keras import backend as K
 from keras.models import Sequential
-from keras.layers import Dense, LeakyReLU
+from keras.layers import Activation, Dense, LeakyReLU
 import numpy as np
 import argparse
 
@@ -100,12 +100,39 @@ def train(toml_file, data_file):
     return model, btree_indices
 
 
+def activation_toml(activation):
+    """The TOML value naming a Keras activation function."""
+    name = activation.__name__
+    if name not in ('linear', 'relu', 'tanh', 'sigmoid'):
+        raise ValueError("Unsupported activation: {}".format(name))
+    return '"{}"'.format(name)
+
+
+def layer_activation_toml(layer):
+    """The TOML value naming the activation computed by a Keras layer without
+    weights."""
+    if isinstance(layer, LeakyReLU):
+        return '{{ name = "leaky_relu", alpha = {0:f} }}'.format(
+            float(K.get_value(layer.alpha)))
+    if isinstance(layer, Activation):
+        return activation_toml(layer.activation)
+    raise ValueError("Unsupported layer: {}".format(layer.name))
+
+
 def save(filename, model, btree_indices):
     with open(filename, 'w') as f:
+        # the activation of each `Dense` layer, replaced by the activation
+        # layer following it, if any
+        activations = []
         j = 0
         for layer in model.layers:
             weight_list = layer.get_weights()
             if not weight_list:
+                if not activations or activations[-1] != '"linear"':
+                    raise ValueError(
+                        "Activation layer {} must follow a linear layer"
+                        .format(layer.name))
+                activations[-1] = layer_activation_toml(layer)
                 continue
             f.write("layer{} = [".format(j))
             for array in weight_list:
@@ -115,7 +142,10 @@ def save(filename, model, btree_indices):
                     f.write("{0:f}, ".format(item))
                 f.write('], ')
             f.write("]\n")
+            activations.append(activation_toml(layer.activation))
             j += 1
+        for j, activation in enumerate(activations):
+            f.write("activation{} = {}\n".format(j, activation))
         f.write("btree_indices = {}\n".format(btree_indices))
 
 
@@ -0,0 +1,165 @@
+//! A growable array whose clones share storage until they are written to.
+//!
+//! `BTree` keeps its nodes in arenas of this type so that `BTree::snapshot`
+//! can be cheap. The items are stored in fixed size chunks, each behind an
+//! `Arc`, so cloning an `Arena` only copies the list of chunk pointers. Writing
+//! to an item through `IndexMut` copies its chunk first if another clone still
+//! refers to it (with `Arc::make_mut`), so a write after a clone copies only
+//! the chunks holding the items it changes.
+
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::{Index, IndexMut};
+use std::sync::Arc;
+
+// Items per chunk. Smaller chunks copy less per write after a clone, but make
+// the clone itself copy more pointers.
+pub const CHUNK: usize = 32;
+
+#[derive(Clone)]
+pub struct Arena<T> {
+    // Every chunk is full except maybe the last. Slots past `len` hold copies
+    // of other items and are never read.
+    chunks: Vec<Arc<[T; CHUNK]>>,
+    len: usize,
+}
+
+impl<T> Arena<T> {
+    pub fn new() -> Self {
+        Arena {
+            chunks: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The number of items the arena has room for without allocating.
+    pub fn capacity(&self) -> usize {
+        self.chunks.len() * CHUNK
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> + '_ {
+        self.chunks.iter().flat_map(|c| c.iter()).take(self.len)
+    }
+
+    /// The number of chunks `self` and `other` share.
+    #[cfg(test)]
+    pub fn shared_chunks(&self, other: &Self) -> usize {
+        self.chunks
+            .iter()
+            .zip(other.chunks.iter())
+            .filter(|(a, b)| Arc::ptr_eq(a, b))
+            .count()
+    }
+}
+
+impl<T> Arena<T>
+where
+    T: Copy,
+{
+    pub fn push(&mut self, item: T) {
+        if self.len == self.capacity() {
+            self.chunks.push(Arc::new([item; CHUNK]));
+        } else {
+            Arc::make_mut(&mut self.chunks[self.len / CHUNK])[self.len % CHUNK] = item;
+        }
+        self.len += 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+        self.len = 0;
+    }
+
+    /// Make room for at least `additional` more items.
+    pub fn reserve(&mut self, additional: usize) {
+        let chunks = (self.len + additional).div_ceil(CHUNK);
+        self.chunks
+            .reserve(chunks.saturating_sub(self.chunks.len()));
+    }
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Index<usize> for Arena<T> {
+    type Output = T;
+
+    fn index(&self, i: usize) -> &T {
+        assert!(i < self.len, "Arena: index out of bounds");
+        &self.chunks[i / CHUNK][i % CHUNK]
+    }
+}
+
+impl<T> IndexMut<usize> for Arena<T>
+where
+    T: Clone,
+{
+    fn index_mut(&mut self, i: usize) -> &mut T {
+        assert!(i < self.len, "Arena: index out of bounds");
+        &mut Arc::make_mut(&mut self.chunks[i / CHUNK])[i % CHUNK]
+    }
+}
+
+impl<T> fmt::Debug for Arena<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T> PartialEq for Arena<T>
+where
+    T: PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+impl<T> Eq for Arena<T> where T: Eq {}
+
+impl<T> Hash for Arena<T>
+where
+    T: Hash,
+{
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len.hash(state);
+        for item in self.iter() {
+            item.hash(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clones_share_until_written() {
+        let mut a = Arena::new();
+        for i in 0..1000u32 {
+            a.push(i);
+        }
+        let b = a.clone();
+        assert_eq!(a.shared_chunks(&b), 1000usize.div_ceil(CHUNK));
+
+        a[5] = 0;
+        a[999] = 0;
+        a.push(1000);
+        assert_eq!(a.shared_chunks(&b), 1000usize.div_ceil(CHUNK) - 2);
+        assert_eq!(a.len(), 1001);
+        assert_eq!(b.len(), 1000);
+        assert!(b.iter().cloned().eq(0..1000));
+        assert_eq!((a[4], a[5], a[998], a[999], a[1000]), (4, 0, 998, 0, 1000));
+        assert!(a != b);
+    }
+}
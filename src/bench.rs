@@ -15,13 +15,15 @@ pub fn duration_to_secs(dur: Duration) -> f64 {
 
 /// Randomly sample `count` keys from `data`, call `eval_many` on `model`, and
 /// return how long `eval_many` took.
-pub fn bench<M>(model: &M, data: &[f32], count: usize) -> Duration
+pub fn bench<K, I, M>(model: &M, data: &[K], count: usize) -> Duration
 where
-    M: Model<f32, u32>,
+    K: Copy,
+    I: Copy,
+    M: Model<K, I>,
 {
     let mut rng = XorShiftRng::from_entropy();
 
-    let keys: Vec<f32> = {
+    let keys: Vec<K> = {
         let dist = Uniform::new(0, count);
         let mut vec = Vec::with_capacity(count);
         for _ in 0..count {
@@ -0,0 +1,684 @@
+//! A B+ Tree: like `btree::BTree`, but every key and index is stored in a leaf,
+//! the leaves are linked to their siblings, and interior nodes only hold
+//! separator keys to guide searches.
+//!
+//! This is the layout databases use, and it makes scans cheap: after finding
+//! the first leaf, a scan just follows the links from leaf to leaf rather than
+//! walking up and down the tree. Scans are done with a `Cursor`, or with the
+//! `iter` and `range` iterators built on it.
+//!
+//! As with `BTree`, nodes are kept in `Vec`s and identified by `u32` indices,
+//! and duplicate keys are kept in insertion order. Delete is not implemented.
+
+use std::ops::{Bound, RangeBounds};
+
+use key::Key;
+use model::Model;
+use node_search::{Binary, NodeSearch};
+
+// Marks a missing sibling link.
+const NONE: u32 = 0xFFFFFFFF;
+
+// As in `btree`, arrays of `2 * T` stand in for arrays of `2 * T - 1`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+struct Leaf<K, I, const T: usize> {
+    keys: [[K; T]; 2],
+    indices: [[I; T]; 2],
+    len: u32,
+    prev: u32,
+    next: u32,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+struct Interior<K, const T: usize> {
+    // `keys[i]` separates `children[i]` from `children[i + 1]`: every key
+    // under `children[i]` is at most `keys[i]` and every key under
+    // `children[i + 1]` is at least `keys[i]`
+    keys: [[K; T]; 2],
+    children: [[u32; T]; 2],
+
+    // the number of keys; there is one more child than this
+    len: u32,
+}
+
+/// A B+ Tree mapping keys to indices, with minimum degree `T`.
+///
+/// Leaves hold between `T - 1` and `2 * T - 1` entries and interior nodes
+/// between `T - 1` and `2 * T - 1` separator keys, except at the root.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct BPlusTree<K, I, const T: usize = 8> {
+    leaves: Vec<Leaf<K, I, T>>,
+    interiors: Vec<Interior<K, T>>,
+
+    // index into `leaves` if `height` is 0, and into `interiors` otherwise
+    root: u32,
+
+    // the number of interior levels
+    height: u32,
+
+    len: usize,
+}
+
+impl<K, I, const T: usize> Default for Leaf<K, I, T>
+where
+    K: Copy + Default,
+    I: Copy + Default,
+{
+    fn default() -> Self {
+        Leaf {
+            keys: [[K::default(); T]; 2],
+            indices: [[I::default(); T]; 2],
+            len: 0,
+            prev: NONE,
+            next: NONE,
+        }
+    }
+}
+
+impl<K, I, const T: usize> Default for BPlusTree<K, I, T>
+where
+    K: Copy + Default,
+    I: Copy + Default,
+{
+    fn default() -> Self {
+        assert!(T >= 2, "BPlusTree: the minimum degree must be at least 2");
+        BPlusTree {
+            leaves: vec![Leaf::default()],
+            interiors: Vec::new(),
+            root: 0,
+            height: 0,
+            len: 0,
+        }
+    }
+}
+
+// The number of keys in `keys`, which must be sorted, that are at most `key`.
+fn rank_after<K>(keys: &[K], key: K) -> usize
+where
+    K: Key,
+{
+    let mut i = keys.len();
+    while i > 0 && key.key_lt(&keys[i - 1]) {
+        i -= 1;
+    }
+    i
+}
+
+impl<K, I, const T: usize> BPlusTree<K, I, T>
+where
+    K: Key,
+    I: Copy + Default,
+{
+    /// Create a new, empty B+ Tree.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Build a B+ Tree from `(key, index)` pairs sorted by key, bottom up and
+    /// with the nodes as full as possible.
+    pub fn from_sorted(pairs: &[(K, I)]) -> Self {
+        debug_assert!(pairs.windows(2).all(|w| w[0].0.key_le(&w[1].0)));
+
+        let mut tree = Self::default();
+        let n = pairs.len();
+        let groups = n.div_ceil(2 * T - 1).max(1);
+        tree.leaves.clear();
+        let mut level = Vec::with_capacity(groups);
+        let mut separators = Vec::with_capacity(groups - 1);
+        let mut next = 0;
+        for j in 0..groups {
+            let count = n / groups + if j < n % groups { 1 } else { 0 };
+            let x = tree.leaves.len() as u32;
+            let mut leaf = Leaf::default();
+            for (k, &(key, index)) in pairs[next..next + count].iter().enumerate() {
+                leaf.keys.as_flattened_mut()[k] = key;
+                leaf.indices.as_flattened_mut()[k] = index;
+            }
+            leaf.len = count as u32;
+            leaf.prev = if j == 0 { NONE } else { x - 1 };
+            leaf.next = if j + 1 == groups { NONE } else { x + 1 };
+            if j > 0 {
+                separators.push(pairs[next].0);
+            }
+            next += count;
+            tree.leaves.push(leaf);
+            level.push(x);
+        }
+
+        while level.len() > 1 {
+            let groups = level.len().div_ceil(2 * T);
+            let mut parents = Vec::with_capacity(groups);
+            let mut parent_separators = Vec::with_capacity(groups - 1);
+            let mut next = 0;
+            for j in 0..groups {
+                let count = level.len() / groups + if j < level.len() % groups { 1 } else { 0 };
+                let x = tree.alloc_interior();
+                let node = &mut tree.interiors[x as usize];
+                node.children.as_flattened_mut()[..count]
+                    .copy_from_slice(&level[next..next + count]);
+                node.keys.as_flattened_mut()[..count - 1]
+                    .copy_from_slice(&separators[next..next + count - 1]);
+                node.len = (count - 1) as u32;
+                next += count;
+                if j + 1 < groups {
+                    parent_separators.push(separators[next - 1]);
+                }
+                parents.push(x);
+            }
+            level = parents;
+            separators = parent_separators;
+            tree.height += 1;
+        }
+
+        tree.root = level[0];
+        tree.len = n;
+        tree
+    }
+
+    /// The number of entries in the tree.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the tree has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Find the index with this key that was inserted before any other index
+    /// with this key, or `None` if the key is not in the tree.
+    pub fn search(&self, key: K) -> Option<I> {
+        match self.lower_bound(key) {
+            Some((k, index)) if k.key_eq(&key) => Some(index),
+            _ => None,
+        }
+    }
+
+    /// The first entry whose key is not less than `key`.
+    pub fn lower_bound(&self, key: K) -> Option<(K, I)> {
+        self.lower_bound_cursor(key).get()
+    }
+
+    /// The first entry whose key is greater than `key`.
+    pub fn upper_bound(&self, key: K) -> Option<(K, I)> {
+        self.upper_bound_cursor(key).get()
+    }
+
+    /// A cursor at the first entry, or at the end if the tree is empty.
+    pub fn cursor_front(&self) -> Cursor<'_, K, I, T> {
+        let mut x = self.root;
+        for _ in 0..self.height {
+            x = self.interiors[x as usize].children[0][0];
+        }
+        Cursor::new(self, x, 0)
+    }
+
+    /// A cursor at the last entry, or at the end if the tree is empty.
+    pub fn cursor_back(&self) -> Cursor<'_, K, I, T> {
+        let mut x = self.root;
+        for _ in 0..self.height {
+            let node = &self.interiors[x as usize];
+            x = node.children.as_flattened()[node.len as usize];
+        }
+        let len = self.leaves[x as usize].len as usize;
+        if len == 0 {
+            Cursor::new(self, NONE, 0)
+        } else {
+            Cursor::new(self, x, len - 1)
+        }
+    }
+
+    /// A cursor at the first entry whose key is not less than `key`, or at
+    /// the end if there is none.
+    pub fn lower_bound_cursor(&self, key: K) -> Cursor<'_, K, I, T> {
+        let mut x = self.root;
+        for _ in 0..self.height {
+            let node = &self.interiors[x as usize];
+            let i = Binary::rank(&node.keys.as_flattened()[..node.len as usize], key);
+            x = node.children.as_flattened()[i];
+        }
+        let leaf = &self.leaves[x as usize];
+        let i = Binary::rank(&leaf.keys.as_flattened()[..leaf.len as usize], key);
+        Cursor::new(self, x, i)
+    }
+
+    /// A cursor at the first entry whose key is greater than `key`, or at the
+    /// end if there is none.
+    pub fn upper_bound_cursor(&self, key: K) -> Cursor<'_, K, I, T> {
+        let mut x = self.root;
+        for _ in 0..self.height {
+            let node = &self.interiors[x as usize];
+            let i = rank_after(&node.keys.as_flattened()[..node.len as usize], key);
+            x = node.children.as_flattened()[i];
+        }
+        let leaf = &self.leaves[x as usize];
+        let i = rank_after(&leaf.keys.as_flattened()[..leaf.len as usize], key);
+        Cursor::new(self, x, i)
+    }
+
+    /// An iterator over all entries, in key order.
+    pub fn iter(&self) -> Range<'_, K, I, T> {
+        self.range(..)
+    }
+
+    /// An iterator over the entries whose keys lie in `range`, in key order.
+    pub fn range<R>(&self, range: R) -> Range<'_, K, I, T>
+    where
+        R: RangeBounds<K>,
+    {
+        let cursor = match range.start_bound() {
+            Bound::Included(&lo) => self.lower_bound_cursor(lo),
+            Bound::Excluded(&lo) => self.upper_bound_cursor(lo),
+            Bound::Unbounded => self.cursor_front(),
+        };
+        Range {
+            cursor,
+            end: range.end_bound().cloned(),
+        }
+    }
+
+    /// Insert `key` into the tree, mapping to `index`. If the key is already
+    /// present, the new entry goes after the existing ones.
+    pub fn insert(&mut self, key: K, index: I) {
+        if self.is_full(self.root, self.height) {
+            let s = self.alloc_interior();
+            self.interiors[s as usize].children[0][0] = self.root;
+            self.root = s;
+            self.height += 1;
+            self.split_child(s, 0, self.height - 1);
+        }
+
+        let mut x = self.root;
+        for level in (1..self.height + 1).rev() {
+            let mut i = {
+                let node = &self.interiors[x as usize];
+                rank_after(&node.keys.as_flattened()[..node.len as usize], key)
+            };
+            let child = self.interiors[x as usize].children.as_flattened()[i];
+            if self.is_full(child, level - 1) {
+                self.split_child(x, i, level - 1);
+                if self.interiors[x as usize].keys.as_flattened()[i].key_le(&key) {
+                    i += 1;
+                }
+            }
+            x = self.interiors[x as usize].children.as_flattened()[i];
+        }
+
+        let leaf = &mut self.leaves[x as usize];
+        let len = leaf.len as usize;
+        let i = rank_after(&leaf.keys.as_flattened()[..len], key);
+        let keys = leaf.keys.as_flattened_mut();
+        keys.copy_within(i..len, i + 1);
+        keys[i] = key;
+        let indices = leaf.indices.as_flattened_mut();
+        indices.copy_within(i..len, i + 1);
+        indices[i] = index;
+        leaf.len += 1;
+        self.len += 1;
+    }
+
+    // Whether node `x` at `level` (0 for leaves) has no room for another key.
+    fn is_full(&self, x: u32, level: u32) -> bool {
+        let len = if level == 0 {
+            self.leaves[x as usize].len
+        } else {
+            self.interiors[x as usize].len
+        };
+        len as usize == 2 * T - 1
+    }
+
+    fn alloc_interior(&mut self) -> u32 {
+        self.interiors.push(Interior {
+            keys: [[K::default(); T]; 2],
+            children: [[0; T]; 2],
+            len: 0,
+        });
+        (self.interiors.len() - 1) as u32
+    }
+
+    // Split the full child `i` of `x`, which is at `level`. A leaf keeps its
+    // first `T - 1` entries and a copy of the first key of the new leaf moves
+    // up; an interior node keeps its first `T - 1` keys and its middle key
+    // moves up.
+    fn split_child(&mut self, x: u32, i: usize, level: u32) {
+        let y = self.interiors[x as usize].children.as_flattened()[i];
+        let (z, separator) = if level == 0 {
+            let mut leaf = self.leaves[y as usize];
+            let z = self.leaves.len() as u32;
+            leaf.keys
+                .as_flattened_mut()
+                .copy_within(T - 1..2 * T - 1, 0);
+            leaf.indices
+                .as_flattened_mut()
+                .copy_within(T - 1..2 * T - 1, 0);
+            leaf.len = T as u32;
+            leaf.prev = y;
+            if leaf.next != NONE {
+                self.leaves[leaf.next as usize].prev = z;
+            }
+            let separator = leaf.keys[0][0];
+            self.leaves.push(leaf);
+            let left = &mut self.leaves[y as usize];
+            left.len = (T - 1) as u32;
+            left.next = z;
+            (z, separator)
+        } else {
+            let z = self.alloc_interior();
+            let left = self.interiors[y as usize];
+            let right = &mut self.interiors[z as usize];
+            right.keys.as_flattened_mut()[..T - 1]
+                .copy_from_slice(&left.keys.as_flattened()[T..2 * T - 1]);
+            right.children.as_flattened_mut()[..T]
+                .copy_from_slice(&left.children.as_flattened()[T..2 * T]);
+            right.len = (T - 1) as u32;
+            self.interiors[y as usize].len = (T - 1) as u32;
+            (z, left.keys.as_flattened()[T - 1])
+        };
+
+        let parent = &mut self.interiors[x as usize];
+        let len = parent.len as usize;
+        let keys = parent.keys.as_flattened_mut();
+        keys.copy_within(i..len, i + 1);
+        keys[i] = separator;
+        let children = parent.children.as_flattened_mut();
+        children.copy_within(i + 1..len + 1, i + 2);
+        children[i + 1] = z;
+        parent.len += 1;
+    }
+}
+
+impl<K, I, const T: usize> Model<K, I> for BPlusTree<K, I, T>
+where
+    K: Key,
+    I: Copy + Default,
+{
+    fn eval(&self, key: K) -> Option<I> {
+        self.search(key)
+    }
+
+    fn eval_lower_bound(&self, key: K) -> Option<I> {
+        self.lower_bound(key).map(|(_, index)| index)
+    }
+
+    fn eval_upper_bound(&self, key: K) -> Option<I> {
+        self.upper_bound(key).map(|(_, index)| index)
+    }
+}
+
+/// A position in a `BPlusTree`: either at one of its entries, or at the end.
+///
+/// Moving forward from the last entry, or backward from the first, goes to
+/// the end; moving forward from the end goes to the first entry, and backward
+/// to the last.
+#[derive(Clone, Debug)]
+pub struct Cursor<'a, K: 'a, I: 'a, const T: usize = 8> {
+    tree: &'a BPlusTree<K, I, T>,
+
+    // the leaf and position in it, or `NONE` at the end
+    leaf: u32,
+    pos: usize,
+}
+
+impl<'a, K, I, const T: usize> Cursor<'a, K, I, T>
+where
+    K: Key,
+    I: Copy + Default,
+{
+    // A cursor at position `pos` of `leaf`, or at the start of the following
+    // leaf if `pos` is past the last entry.
+    fn new(tree: &'a BPlusTree<K, I, T>, leaf: u32, pos: usize) -> Self {
+        let mut cursor = Cursor { tree, leaf, pos };
+        if leaf != NONE && pos >= tree.leaves[leaf as usize].len as usize {
+            cursor.leaf = tree.leaves[leaf as usize].next;
+            cursor.pos = 0;
+        }
+        cursor
+    }
+
+    /// The entry at the cursor, or `None` at the end.
+    pub fn get(&self) -> Option<(K, I)> {
+        if self.leaf == NONE {
+            None
+        } else {
+            let leaf = &self.tree.leaves[self.leaf as usize];
+            Some((
+                leaf.keys.as_flattened()[self.pos],
+                leaf.indices.as_flattened()[self.pos],
+            ))
+        }
+    }
+
+    /// The key at the cursor, or `None` at the end.
+    pub fn key(&self) -> Option<K> {
+        self.get().map(|(key, _)| key)
+    }
+
+    /// The index at the cursor, or `None` at the end.
+    pub fn index(&self) -> Option<I> {
+        self.get().map(|(_, index)| index)
+    }
+
+    /// Move to the next entry.
+    pub fn move_next(&mut self) {
+        if self.leaf == NONE {
+            *self = self.tree.cursor_front();
+        } else {
+            *self = Cursor::new(self.tree, self.leaf, self.pos + 1);
+        }
+    }
+
+    /// Move to the previous entry.
+    pub fn move_prev(&mut self) {
+        if self.leaf == NONE {
+            *self = self.tree.cursor_back();
+        } else if self.pos > 0 {
+            self.pos -= 1;
+        } else {
+            self.leaf = self.tree.leaves[self.leaf as usize].prev;
+            if self.leaf != NONE {
+                self.pos = self.tree.leaves[self.leaf as usize].len as usize - 1;
+            }
+        }
+    }
+}
+
+/// An iterator over the entries of a `BPlusTree` whose keys lie in some range.
+///
+/// Created by `BPlusTree::iter` and `BPlusTree::range`.
+#[derive(Clone, Debug)]
+pub struct Range<'a, K: 'a, I: 'a, const T: usize = 8> {
+    cursor: Cursor<'a, K, I, T>,
+    end: Bound<K>,
+}
+
+impl<'a, K, I, const T: usize> Iterator for Range<'a, K, I, T>
+where
+    K: Key,
+    I: Copy + Default,
+{
+    type Item = (K, I);
+
+    fn next(&mut self) -> Option<(K, I)> {
+        let (key, index) = self.cursor.get()?;
+        let in_range = match self.end {
+            Bound::Included(hi) => key.key_le(&hi),
+            Bound::Excluded(hi) => key.key_lt(&hi),
+            Bound::Unbounded => true,
+        };
+        if in_range {
+            self.cursor.move_next();
+            Some((key, index))
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a, K, I, const T: usize> IntoIterator for &'a BPlusTree<K, I, T>
+where
+    K: Key,
+    I: Copy + Default,
+{
+    type Item = (K, I);
+    type IntoIter = Range<'a, K, I, T>;
+
+    fn into_iter(self) -> Range<'a, K, I, T> {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    // Check node sizes, leaf depth and the leaf links.
+    fn assert_well_formed<K, I, const T: usize>(b: &BPlusTree<K, I, T>)
+    where
+        K: Key,
+        I: Copy + Default,
+    {
+        fn walk<K, I, const T: usize>(
+            b: &BPlusTree<K, I, T>,
+            x: u32,
+            level: u32,
+            leaves: &mut Vec<u32>,
+        ) {
+            let len = if level == 0 {
+                leaves.push(x);
+                b.leaves[x as usize].len as usize
+            } else {
+                b.interiors[x as usize].len as usize
+            };
+            assert!(len < 2 * T);
+            if x != b.root || level != b.height {
+                assert!(len >= T - 1);
+            }
+            if level > 0 {
+                for &c in &b.interiors[x as usize].children.as_flattened()[..len + 1] {
+                    walk(b, c, level - 1, leaves);
+                }
+            }
+        }
+
+        let mut leaves = Vec::new();
+        walk(b, b.root, b.height, &mut leaves);
+        for (j, &x) in leaves.iter().enumerate() {
+            let leaf = &b.leaves[x as usize];
+            let prev = if j == 0 { NONE } else { leaves[j - 1] };
+            let next = leaves.get(j + 1).cloned().unwrap_or(NONE);
+            assert_eq!((leaf.prev, leaf.next), (prev, next));
+        }
+    }
+
+    fn random_tree<const T: usize>(seed: u8) -> (BPlusTree<u32, u32, T>, Vec<(u32, u32)>) {
+        let mut rng = XorShiftRng::from_seed([seed; 16]);
+        let mut b = BPlusTree::new();
+        let mut reference = Vec::new();
+        for i in 0..5000 {
+            let key = if rng.gen_range(0, 4) == 0 {
+                rng.gen_range(0, 5)
+            } else {
+                rng.gen_range(0, 4000)
+            };
+            b.insert(key, i);
+            reference.push((key, i));
+        }
+        // a stable sort keeps duplicates in insertion order
+        reference.sort_by_key(|p| p.0);
+        (b, reference)
+    }
+
+    #[test]
+    fn insert_and_search() {
+        let (b, reference) = random_tree::<8>(1);
+        assert_well_formed(&b);
+        assert_eq!(b.len(), reference.len());
+        assert_eq!(b.iter().collect::<Vec<_>>(), reference);
+        for key in 0..4001 {
+            let lower = reference.iter().find(|p| p.0 >= key).cloned();
+            let upper = reference.iter().find(|p| p.0 > key).cloned();
+            assert_eq!(b.lower_bound(key), lower);
+            assert_eq!(b.upper_bound(key), upper);
+            assert_eq!(b.search(key), lower.filter(|p| p.0 == key).map(|p| p.1));
+            assert_eq!(b.eval_lower_bound(key), lower.map(|p| p.1));
+        }
+    }
+
+    #[test]
+    fn degrees() {
+        let (b, reference) = random_tree::<2>(2);
+        assert_well_formed(&b);
+        assert_eq!(b.iter().collect::<Vec<_>>(), reference);
+        let (b, reference) = random_tree::<64>(3);
+        assert_well_formed(&b);
+        assert_eq!(b.iter().collect::<Vec<_>>(), reference);
+    }
+
+    #[test]
+    fn range() {
+        let (b, reference) = random_tree::<4>(4);
+        for &(lo, hi) in &[(0, 0), (0, 5), (3, 3000), (100, 101), (3990, 5000)] {
+            let expected: Vec<_> = reference
+                .iter()
+                .cloned()
+                .filter(|p| lo <= p.0 && p.0 < hi)
+                .collect();
+            assert_eq!(b.range(lo..hi).collect::<Vec<_>>(), expected);
+            let expected: Vec<_> = reference
+                .iter()
+                .cloned()
+                .filter(|p| lo < p.0 && p.0 <= hi)
+                .collect();
+            let bounds = (Bound::Excluded(lo), Bound::Included(hi));
+            assert_eq!(b.range(bounds).collect::<Vec<_>>(), expected);
+        }
+    }
+
+    #[test]
+    fn cursor() {
+        let (b, reference) = random_tree::<3>(5);
+        let mut cursor = b.cursor_front();
+        for &pair in reference.iter() {
+            assert_eq!(cursor.get(), Some(pair));
+            cursor.move_next();
+        }
+        assert_eq!(cursor.get(), None);
+        for &pair in reference.iter().rev() {
+            cursor.move_prev();
+            assert_eq!(cursor.get(), Some(pair));
+        }
+        cursor.move_prev();
+        assert_eq!(cursor.get(), None);
+        cursor.move_next();
+        assert_eq!(cursor.get(), reference.first().cloned());
+
+        let mut cursor = b.lower_bound_cursor(2000);
+        let start = reference.iter().position(|p| p.0 >= 2000).unwrap();
+        cursor.move_prev();
+        assert_eq!(cursor.get(), Some(reference[start - 1]));
+
+        let empty: BPlusTree<u32, u32> = BPlusTree::new();
+        assert_eq!(empty.cursor_front().get(), None);
+        assert_eq!(empty.cursor_back().get(), None);
+        assert_eq!(empty.iter().count(), 0);
+    }
+
+    #[test]
+    fn from_sorted() {
+        for &n in &[0, 1, 15, 16, 100, 1000, 10000] {
+            let pairs: Vec<(u32, u32)> = (0..n).map(|i| (i / 3, i)).collect();
+            let mut b: BPlusTree<u32, u32> = BPlusTree::from_sorted(&pairs);
+            assert_well_formed(&b);
+            assert_eq!(b.len(), pairs.len());
+            assert_eq!(b.iter().collect::<Vec<_>>(), pairs);
+            for &(key, _) in pairs.iter() {
+                assert_eq!(b.search(key), Some(3 * key));
+            }
+            b.insert(n, n);
+            assert_well_formed(&b);
+            assert_eq!(b.cursor_back().get(), Some((n, n)));
+        }
+    }
+}
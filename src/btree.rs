@@ -1,54 +1,127 @@
 //! Simple implementation of B Trees. Refer to Cormen et al (2009).
 //!
 //! Like Cormen's description, but also maintains an index associated with each
-//! key. Currently delete is not implemented. Also, this is just an in-memory B
-//! Tree, so I don't worry about disk reads and writes.
+//! key. Also, this is just an in-memory B Tree, so I don't worry about disk
+//! reads and writes.
 
+use std::error::Error;
+use std::fmt;
+use std::iter::FromIterator;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+
+use arena::Arena;
+use frozen_btree::FrozenBTree;
+use key::Key;
 use model::Model;
+use node_search::{Binary, NodeSearch};
+
+// Value of `BTreeNode::children` for a leaf.
+const LEAF: u32 = 0xFFFFFFFF;
+
+// The number of lookups `search_many` runs together. It should be enough that
+// the prefetches for one level of a group are still in flight while the
+// others in the group are searched, but few enough that the nodes of a whole
+// group fit in the L1 cache.
+pub(crate) const GROUP: usize = 16;
+
+// Ask the CPU to start loading `item` into the cache, without waiting for it.
+#[inline(always)]
+pub(crate) fn prefetch<T: ?Sized>(item: &T) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
 
-const T: usize = 8;
+        let p = item as *const T as *const i8;
+        for offset in (0..mem::size_of_val(item)).step_by(64) {
+            unsafe { _mm_prefetch::<_MM_HINT_T0>(p.wrapping_add(offset)) }
+        }
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        let _ = item;
+    }
+}
 
-#[derive(Copy, Clone, Default, Debug, Eq, PartialEq, Hash)]
-struct BTreeNode<K, I> {
-    keys: [K; 2 * T - 1],
-    indices: [I; 2 * T - 1],
+// Stable Rust can't use `2 * T - 1` as an array length when `T` is a const
+// parameter, so each node has room for `2 * T` keys, stored as two arrays of
+// `T`, and the last slot is never used. The accessors below present the keys as
+// a single slice.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+struct BTreeNode<K, I, const T: usize> {
+    keys: [[K; T]; 2],
+    indices: [[I; T]; 2],
     key_count: u32,
 
-    // Either 0xFFFFFFFF if a leaf, or else an index into the `children` Vec of
-    // BTree
+    // Either LEAF, or else an index into the `children` Vec of BTree
     children: u32,
 }
 
+impl<K, I, const T: usize> Default for BTreeNode<K, I, T>
+where
+    K: Copy + Default,
+    I: Copy + Default,
+{
+    fn default() -> Self {
+        BTreeNode {
+            keys: [[K::default(); T]; 2],
+            indices: [[I::default(); T]; 2],
+            key_count: 0,
+            children: LEAF,
+        }
+    }
+}
+
 /// A BTree, parametrized by key and index type.
 ///
-/// The minimum degree is fixed at 8. I've made this a fixed constant rather
-/// than some sort of parameter because I want it to be optimized away rather
-/// than a runtime variable, and because Rust currently has issues with
-/// associated consts as array lengths (see rustc github issue #29646). It's
-/// possible to work around this but I'm not taking the time, at least for now.
+/// The same key may be inserted more than once. Entries with equal keys are
+/// kept in the order they were inserted: an insert goes after any equal keys
+/// already in a node, and when a node is split, the entries that stay in the
+/// left half were all inserted before the one moving up to the parent and the
+/// entries in the right half. So iteration, `equal_range` and `search` all see
+/// duplicates in insertion order.
+///
+/// The minimum degree `T` is a const parameter, so it is a compile time
+/// constant in the generated code. It defaults to 8 and must be at least 2.
+/// Each node other than the root holds between `T - 1` and `2 * T - 1` keys.
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
-pub struct BTree<K, I> {
-    nodes: Vec<BTreeNode<K, I>>,
+pub struct BTree<K, I, const T: usize = 8> {
+    // Both arenas share their storage with snapshots of the tree until it is
+    // written to.
+    nodes: Arena<BTreeNode<K, I, T>>,
 
-    // each item is an index into `nodes`
-    children: Vec<[u32; 2 * T]>,
+    // each item is an array of `2 * T` indices into `nodes`
+    children: Arena<[[u32; T]; 2]>,
 
     // index into `nodes` giving the root node
     root: u32,
+
+    // slots in `nodes` and `children` freed by `remove`, to be reused before
+    // either Vec grows
+    free_nodes: Vec<u32>,
+    free_children: Vec<u32>,
+
+    // the number of entries
+    len: usize,
 }
 
-impl<K, I> Default for BTree<K, I>
+impl<K, I, const T: usize> Default for BTree<K, I, T>
 where
     K: Copy + Default,
     I: Copy + Default,
 {
     fn default() -> Self {
-        let mut root = BTreeNode::default();
-        root.children = 0xFFFFFFFF;
+        assert!(T >= 2, "BTree: the minimum degree must be at least 2");
+        let mut nodes = Arena::new();
+        nodes.push(BTreeNode::default());
         BTree {
-            nodes: vec![root],
-            children: Vec::new(),
+            nodes,
+            children: Arena::new(),
             root: 0,
+            free_nodes: Vec::new(),
+            free_children: Vec::new(),
+            len: 0,
         }
     }
 }
@@ -59,9 +132,12 @@ where
 // retrieve a BTreeNode, methods are provided to retrieve features of a node
 // given the index. For instance, `fn keys(&self, node: u32) -> &[K]` retrieves
 // a slice of the keys associated with a given node.
-impl<K, I> BTree<K, I>
+//
+// New nodes and children arrays are obtained through `alloc_node` and
+// `alloc_children`, which reuse slots released by `free_node`.
+impl<K, I, const T: usize> BTree<K, I, T>
 where
-    K: Copy + Default + PartialEq + PartialOrd,
+    K: Key,
     I: Copy + Default,
 {
     /// Create a new BTree.
@@ -69,10 +145,226 @@ where
         Default::default()
     }
 
+    /// Build a BTree from `(key, index)` pairs sorted by key.
+    ///
+    /// This works bottom up in linear time, and the nodes it creates are as
+    /// full as possible, so it is much faster than inserting the pairs one at
+    /// a time and gives a shallower tree. Pairs with equal keys are treated as
+    /// if they had been inserted in the order they appear in `pairs`.
+    pub fn from_sorted(pairs: &[(K, I)]) -> Self {
+        Self::build_sorted(pairs.len(), |i| pairs[i])
+    }
+
+    /// Like `from_sorted`, but with the keys and indices in separate slices,
+    /// so that `indices[i]` is the index of `keys[i]`.
+    ///
+    /// Panics if the slices have different lengths.
+    pub fn from_sorted_parts(keys: &[K], indices: &[I]) -> Self {
+        assert_eq!(
+            keys.len(),
+            indices.len(),
+            "from_sorted_parts: keys and indices have different lengths"
+        );
+        Self::build_sorted(keys.len(), |i| (keys[i], indices[i]))
+    }
+
+    /// The number of entries in the tree, counting each duplicate key.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the tree has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether `key` is in the tree.
+    pub fn contains_key(&self, key: K) -> bool {
+        self.search(key).is_some()
+    }
+
+    /// The entry with the smallest key, or `None` if the tree is empty. If
+    /// that key was inserted several times, this is the earliest inserted.
+    pub fn first(&self) -> Option<(K, I)> {
+        let mut x = self.root;
+        while let Some(c) = self.children(x) {
+            x = c[0];
+        }
+        if *self.key_count(x) == 0 {
+            None
+        } else {
+            Some((self.keys(x)[0], self.indices(x)[0]))
+        }
+    }
+
+    /// The entry with the largest key, or `None` if the tree is empty. If
+    /// that key was inserted several times, this is the latest inserted.
+    pub fn last(&self) -> Option<(K, I)> {
+        let mut x = self.root;
+        while let Some(c) = self.children(x) {
+            x = c[*self.key_count(x) as usize];
+        }
+        match *self.key_count(x) as usize {
+            0 => None,
+            n => Some((self.keys(x)[n - 1], self.indices(x)[n - 1])),
+        }
+    }
+
+    /// Remove every entry, releasing the memory the tree used.
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
     /// Find the index with this key that was inserted before any other index
     /// with this key, or `None` if the key is not in the tree.
+    ///
+    /// This searches within each node using `node_search::Binary`; see
+    /// `search_with` to choose another strategy.
     pub fn search(&self, key: K) -> Option<I> {
-        self.rsearch(self.root, key)
+        self.search_with::<Binary>(key)
+    }
+
+    /// Like `search`, but using `S` to search within each node.
+    pub fn search_with<S>(&self, key: K) -> Option<I>
+    where
+        S: NodeSearch<K>,
+    {
+        match self.lower_bound_with::<S>(key) {
+            Some((k, index)) if k.key_eq(&key) => Some(index),
+            _ => None,
+        }
+    }
+
+    /// Search for each of `keys`, storing the result for `keys[i]` in
+    /// `indices[i]`.
+    ///
+    /// This gives the same results as calling `search` for each key, but is
+    /// faster for large trees. A search spends most of its time waiting for
+    /// nodes to arrive from memory, so instead of finishing one search before
+    /// starting the next, this runs a group of them together a level at a
+    /// time, prefetching the nodes each will need for the next level before
+    /// searching the nodes of the others at this one.
+    ///
+    /// Panics if the slices have different lengths.
+    pub fn search_many(&self, keys: &[K], indices: &mut [Option<I>]) {
+        self.search_many_with::<Binary>(keys, indices)
+    }
+
+    /// Like `search_many`, but using `S` to search within each node.
+    pub fn search_many_with<S>(&self, keys: &[K], indices: &mut [Option<I>])
+    where
+        S: NodeSearch<K>,
+    {
+        assert_eq!(
+            keys.len(),
+            indices.len(),
+            "search_many: keys and indices have different lengths"
+        );
+        for (keys, indices) in keys.chunks(GROUP).zip(indices.chunks_mut(GROUP)) {
+            self.search_group::<S>(keys, indices);
+        }
+    }
+
+    /// A view of this tree implementing `Model` with `S` as the strategy for
+    /// searching within each node, to benchmark the strategies against each
+    /// other.
+    pub fn with_node_search<S>(&self) -> WithNodeSearch<'_, K, I, T, S>
+    where
+        S: NodeSearch<K>,
+    {
+        WithNodeSearch {
+            tree: self,
+            phantom: PhantomData,
+        }
+    }
+
+    /// All indices inserted with this key, in the order they were inserted.
+    pub fn search_all(&self, key: K) -> impl Iterator<Item = I> + '_ {
+        self.equal_range(key).map(|(_, index)| index)
+    }
+
+    /// All `(key, index)` pairs with this key, in the order they were
+    /// inserted.
+    pub fn equal_range(&self, key: K) -> Range<'_, K, I, T> {
+        self.range(key..=key)
+    }
+
+    /// The first entry whose key is not less than `key`, or `None` if every
+    /// key in the tree is less than `key`.
+    pub fn lower_bound(&self, key: K) -> Option<(K, I)> {
+        self.lower_bound_with::<Binary>(key)
+    }
+
+    /// Like `lower_bound`, but using `S` to search within each node.
+    pub fn lower_bound_with<S>(&self, key: K) -> Option<(K, I)>
+    where
+        S: NodeSearch<K>,
+    {
+        let mut result = None;
+        let mut x = self.root;
+        loop {
+            let i = S::rank(&self.keys(x)[..*self.key_count(x) as usize], key);
+            if i < *self.key_count(x) as usize {
+                result = Some((self.keys(x)[i], self.indices(x)[i]));
+            }
+            match self.children(x) {
+                None => return result,
+                Some(c) => x = c[i],
+            }
+        }
+    }
+
+    /// The first entry whose key is greater than `key`, or `None` if no key in
+    /// the tree is greater than `key`.
+    pub fn upper_bound(&self, key: K) -> Option<(K, I)> {
+        self.upper_bound_with::<Binary>(key)
+    }
+
+    /// Like `upper_bound`, but using `S` to search within each node.
+    pub fn upper_bound_with<S>(&self, key: K) -> Option<(K, I)>
+    where
+        S: NodeSearch<K>,
+    {
+        let mut result = None;
+        let mut x = self.root;
+        loop {
+            // `S` finds the keys less than `key`, and any equal to it follow
+            let keys = &self.keys(x)[..*self.key_count(x) as usize];
+            let mut i = S::rank(keys, key);
+            while i < keys.len() && keys[i].key_eq(&key) {
+                i += 1;
+            }
+            if i < keys.len() {
+                result = Some((self.keys(x)[i], self.indices(x)[i]));
+            }
+            match self.children(x) {
+                None => return result,
+                Some(c) => x = c[i],
+            }
+        }
+    }
+
+    /// The last entry whose key is less than `key`, or `None` if no key in the
+    /// tree is less than `key`.
+    pub fn predecessor(&self, key: K) -> Option<(K, I)> {
+        let mut result = None;
+        let mut x = self.root;
+        loop {
+            let i = self.find(x, key);
+            if i > 0 {
+                result = Some((self.keys(x)[i - 1], self.indices(x)[i - 1]));
+            }
+            match self.children(x) {
+                None => return result,
+                Some(c) => x = c[i],
+            }
+        }
+    }
+
+    /// The first entry whose key is greater than `key`, or `None` if no key in
+    /// the tree is greater than `key`. This is the same as `upper_bound`.
+    pub fn successor(&self, key: K) -> Option<(K, I)> {
+        self.upper_bound(key)
     }
 
     /// Insert `key` into the tree, mapping to `index`.
@@ -82,84 +374,453 @@ where
     pub fn insert(&mut self, key: K, index: I) {
         let r = self.root;
         if *self.key_count(r) == (2 * T - 1) as u32 {
-            let s = self.nodes.len() as u32;
+            let s = self.alloc_node(false);
             self.root = s;
-            self.nodes.push(Default::default());
-            self.nodes[s as usize].children = self.children.len() as u32;
-            self.children.push(Default::default());
             self.children_mut(s).unwrap()[0] = r;
             self.split_child(s, 0);
             self.insert_nonfull(s, key, index);
         } else {
             self.insert_nonfull(r, key, index);
         }
+        self.len += 1;
+    }
+
+    /// An iterator over the `(key, index)` pairs of the tree, in key order.
+    pub fn iter(&self) -> Iter<'_, K, I, T> {
+        Iter {
+            range: self.range(..),
+            remaining: self.len,
+        }
+    }
+
+    /// An iterator over the `(key, index)` pairs whose keys lie in `range`, in
+    /// key order.
+    ///
+    /// For instance, `tree.range(lo..hi)` visits the keys `k` with
+    /// `lo <= k < hi`.
+    pub fn range<R>(&self, range: R) -> Range<'_, K, I, T>
+    where
+        R: RangeBounds<K>,
+    {
+        Range {
+            tree: self,
+            stack: self.range_start(range.start_bound()),
+            end: range.end_bound().cloned(),
+        }
+    }
+
+    // The stack for a `Range` whose first key is the first one after `start`.
+    fn range_start(&self, start: Bound<&K>) -> Vec<(u32, usize)> {
+        let mut stack = Vec::new();
+        let mut x = self.root;
+        loop {
+            let i = match start {
+                Bound::Included(&lo) => self.find(x, lo),
+                Bound::Excluded(&lo) => self.find_after(x, lo),
+                Bound::Unbounded => 0,
+            };
+            stack.push((x, i));
+            match self.children(x) {
+                None => break,
+                Some(c) => x = c[i],
+            }
+        }
+        stack
+    }
+
+    // Advance the stack of a `Range`, returning the entry it was at, or `None`
+    // once it has passed the last key before `end`.
+    fn range_next(&self, stack: &mut Vec<(u32, usize)>, end: Bound<K>) -> Option<(K, I)> {
+        loop {
+            let (x, i) = *stack.last()?;
+            if i >= *self.key_count(x) as usize {
+                stack.pop();
+                continue;
+            }
+            let key = self.keys(x)[i];
+            let in_range = match end {
+                Bound::Included(hi) => key.key_le(&hi),
+                Bound::Excluded(hi) => key.key_lt(&hi),
+                Bound::Unbounded => true,
+            };
+            if !in_range {
+                stack.clear();
+                return None;
+            }
+            stack.last_mut().unwrap().1 = i + 1;
+            if let Some(c) = self.children(x) {
+                let mut y = c[i + 1];
+                loop {
+                    stack.push((y, 0));
+                    match self.children(y) {
+                        None => break,
+                        Some(c) => y = c[0],
+                    }
+                }
+            }
+            return Some((key, self.indices(x)[i]));
+        }
+    }
+
+    /// Remove `key` from the tree, returning its index, or `None` if the key is
+    /// not in the tree.
+    ///
+    /// If `key` was inserted several times, the entry removed is the earliest
+    /// inserted one still present. Nodes emptied by the removal are put on a
+    /// free list and reused by later inserts.
+    pub fn remove(&mut self, key: K) -> Option<I> {
+        let r = self.root;
+        let result = self.rremove(r, key);
+        if *self.key_count(r) == 0 {
+            if let Some(c) = self.children(r) {
+                self.root = c[0];
+                self.free_node(r);
+            }
+        }
+        if result.is_some() {
+            self.len -= 1;
+        }
+        result
+    }
+
+    /// Check that the tree is well formed, returning a description of the
+    /// first problem found.
+    ///
+    /// This checks that the keys are in order, both within each node and
+    /// relative to the keys in its ancestors; that each node other than the
+    /// root has between `T - 1` and `2 * T - 1` keys; that all leaves are at
+    /// the same depth; and that the child links lead to distinct, allocated
+    /// nodes, with every node either reachable from the root or on the free
+    /// list. It also checks that `len` is the number of entries.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let mut seen = vec![false; self.nodes.len()];
+        seen[self.root as usize] = true;
+        for &x in self.free_nodes.iter() {
+            if x as usize >= self.nodes.len() || seen[x as usize] {
+                return Err(ValidationError::BadFreeNode { node: x });
+            }
+            seen[x as usize] = true;
+        }
+        let mut leaf_depth = None;
+        let mut children_seen = vec![false; self.children.len()];
+        for &c in self.free_children.iter() {
+            if c as usize >= self.children.len() || children_seen[c as usize] {
+                return Err(ValidationError::BadFreeChildren { children: c });
+            }
+            children_seen[c as usize] = true;
+        }
+        let mut context = ValidationContext {
+            seen: &mut seen,
+            children_seen: &mut children_seen,
+            leaf_depth: &mut leaf_depth,
+            key_count: 0,
+        };
+        self.validate_node(self.root, 0, None, None, &mut context)?;
+        if context.key_count != self.len {
+            return Err(ValidationError::Len {
+                len: self.len,
+                count: context.key_count,
+            });
+        }
+        match seen.iter().position(|&s| !s) {
+            Some(x) => Err(ValidationError::Unreachable { node: x as u32 }),
+            None => Ok(()),
+        }
+    }
+
+    /// Summary statistics about the shape and size of the tree.
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            height: 1,
+            node_count: 0,
+            key_count: 0,
+            fill_factor: 0.0,
+            bytes_used: mem::size_of::<Self>()
+                + self.nodes.capacity() * mem::size_of::<BTreeNode<K, I, T>>()
+                + self.children.capacity() * mem::size_of::<[[u32; T]; 2]>()
+                + (self.free_nodes.capacity() + self.free_children.capacity())
+                    * mem::size_of::<u32>(),
+        };
+        let mut x = self.root;
+        while let Some(c) = self.children(x) {
+            stats.height += 1;
+            x = c[0];
+        }
+        let mut stack = vec![self.root];
+        while let Some(x) = stack.pop() {
+            stats.node_count += 1;
+            let count = *self.key_count(x) as usize;
+            stats.key_count += count;
+            if let Some(c) = self.children(x) {
+                stack.extend_from_slice(&c[..count + 1]);
+            }
+        }
+        stats.fill_factor = stats.key_count as f64 / (stats.node_count * (2 * T - 1)) as f64;
+        stats
+    }
+
+    /// A packed, read-only copy of the tree, which takes less memory and is
+    /// faster to search. See `FrozenBTree`.
+    pub fn freeze(&self) -> FrozenBTree<K, I, T> {
+        let pairs: Vec<(K, I)> = self.iter().collect();
+        FrozenBTree::from_sorted(&pairs)
+    }
+
+    /// A read-only view of the tree as it is now, which later changes to the
+    /// tree don't affect.
+    ///
+    /// The snapshot shares the tree's nodes rather than copying them, so this
+    /// only copies a pointer for every 32 nodes. Afterwards, the first write
+    /// to a node still shared with a snapshot copies the 32 nodes around it.
+    pub fn snapshot(&self) -> Snapshot<K, I, T> {
+        Snapshot {
+            tree: BTree {
+                nodes: self.nodes.clone(),
+                children: self.children.clone(),
+                root: self.root,
+                free_nodes: Vec::new(),
+                free_children: Vec::new(),
+                len: self.len,
+            },
+        }
+    }
+
+    fn validate_node(
+        &self,
+        x: u32,
+        depth: usize,
+        lo: Option<K>,
+        hi: Option<K>,
+        context: &mut ValidationContext,
+    ) -> Result<(), ValidationError> {
+        let count = *self.key_count(x) as usize;
+        let min = if x != self.root {
+            T - 1
+        } else if self.children(x).is_some() {
+            1
+        } else {
+            0
+        };
+        if count < min || count > 2 * T - 1 {
+            return Err(ValidationError::KeyCount { node: x, count });
+        }
+        context.key_count += count;
+
+        let keys = &self.keys(x)[..count];
+        for i in 0..count {
+            let after_lo = lo.is_none_or(|lo| lo.key_le(&keys[i]));
+            let before_hi = hi.is_none_or(|hi| keys[i].key_le(&hi));
+            let after_previous = i == 0 || keys[i - 1].key_le(&keys[i]);
+            if !(after_lo && before_hi && after_previous) {
+                return Err(ValidationError::KeyOrder {
+                    node: x,
+                    position: i,
+                });
+            }
+        }
+
+        let slot = self.nodes[x as usize].children;
+        if slot == LEAF {
+            match *context.leaf_depth {
+                None => *context.leaf_depth = Some(depth),
+                Some(d) if d != depth => {
+                    return Err(ValidationError::LeafDepth {
+                        node: x,
+                        depth,
+                        expected: d,
+                    })
+                }
+                _ => {}
+            }
+            return Ok(());
+        }
+
+        if slot as usize >= self.children.len() || context.children_seen[slot as usize] {
+            return Err(ValidationError::BadChildren {
+                node: x,
+                children: slot,
+            });
+        }
+        context.children_seen[slot as usize] = true;
+        for i in 0..count + 1 {
+            let c = self.children[slot as usize].as_flattened()[i];
+            if c as usize >= self.nodes.len() || context.seen[c as usize] {
+                return Err(ValidationError::BadChild {
+                    node: x,
+                    position: i,
+                    child: c,
+                });
+            }
+            context.seen[c as usize] = true;
+            let child_lo = if i == 0 { lo } else { Some(keys[i - 1]) };
+            let child_hi = if i == count { hi } else { Some(keys[i]) };
+            self.validate_node(c, depth + 1, child_lo, child_hi, context)?;
+        }
+        Ok(())
+    }
+
+    // Build a tree from the `n` sorted pairs `pair(0)`, `pair(1)`, ... one
+    // level at a time. Each level is split into as few nodes as possible, with
+    // the keys (or children) spread evenly among them, so every node other
+    // than the root ends up with between `T - 1` and `2 * T - 1` keys.
+    fn build_sorted<F>(n: usize, pair: F) -> Self
+    where
+        F: Fn(usize) -> (K, I),
+    {
+        debug_assert!((1..n).all(|i| pair(i - 1).0.key_le(&pair(i).0)));
+
+        let mut tree = Self::default();
+        tree.nodes.clear();
+        tree.nodes.reserve(n / (2 * T - 1) + 1);
+
+        // Leaves. Each leaf but the last is followed by a separator key, so
+        // a leaf and its separator take up to 2 * T of the pairs.
+        let groups = (n + 2 * T) / (2 * T);
+        let in_leaves = n + 1 - groups;
+        let mut level = Vec::with_capacity(groups);
+        let mut separators = Vec::with_capacity(groups - 1);
+        let mut next = 0;
+        for j in 0..groups {
+            let count = in_leaves / groups + if j < in_leaves % groups { 1 } else { 0 };
+            let x = tree.alloc_node(true);
+            for k in 0..count {
+                let (key, index) = pair(next + k);
+                tree.keys_mut(x)[k] = key;
+                tree.indices_mut(x)[k] = index;
+            }
+            *tree.key_count_mut(x) = count as u32;
+            next += count;
+            if j + 1 < groups {
+                separators.push(pair(next));
+                next += 1;
+            }
+            level.push(x);
+        }
+
+        // Interior levels. A node with `count` children takes the `count - 1`
+        // separators between them, and the separator after its last child
+        // moves up to the next level.
+        while level.len() > 1 {
+            let groups = level.len().div_ceil(2 * T);
+            let mut parents = Vec::with_capacity(groups);
+            let mut parent_separators = Vec::with_capacity(groups - 1);
+            let mut next = 0;
+            for j in 0..groups {
+                let count = level.len() / groups + if j < level.len() % groups { 1 } else { 0 };
+                let x = tree.alloc_node(false);
+                tree.children_mut(x).unwrap()[..count].copy_from_slice(&level[next..next + count]);
+                for k in 0..count - 1 {
+                    let (key, index) = separators[next + k];
+                    tree.keys_mut(x)[k] = key;
+                    tree.indices_mut(x)[k] = index;
+                }
+                *tree.key_count_mut(x) = (count - 1) as u32;
+                next += count;
+                if j + 1 < groups {
+                    parent_separators.push(separators[next - 1]);
+                }
+                parents.push(x);
+            }
+            level = parents;
+            separators = parent_separators;
+        }
+
+        tree.root = level[0];
+        tree.len = n;
+        tree
+    }
+
+    fn alloc_node(&mut self, leaf: bool) -> u32 {
+        let children = if leaf { LEAF } else { self.alloc_children() };
+        let node = BTreeNode {
+            children,
+            ..Default::default()
+        };
+        if let Some(x) = self.free_nodes.pop() {
+            self.nodes[x as usize] = node;
+            x
+        } else {
+            self.nodes.push(node);
+            (self.nodes.len() - 1) as u32
+        }
+    }
+
+    fn alloc_children(&mut self) -> u32 {
+        if let Some(c) = self.free_children.pop() {
+            self.children[c as usize] = [[0; T]; 2];
+            c
+        } else {
+            self.children.push([[0; T]; 2]);
+            (self.children.len() - 1) as u32
+        }
+    }
+
+    fn free_node(&mut self, x: u32) {
+        let children = self.nodes[x as usize].children;
+        if children != LEAF {
+            self.free_children.push(children);
+        }
+        self.free_nodes.push(x);
     }
 
     fn split_child(&mut self, x: u32, i: usize) {
-        let z = self.nodes.len() as u32;
-        self.nodes.push(Default::default());
         let y = self.children(x).expect("No children")[i];
+        let leaf = self.children(y).is_none();
+        let z = self.alloc_node(leaf);
         *self.key_count_mut(z) = (T - 1) as u32;
         for j in 0..T - 1 {
             self.keys_mut(z)[j] = self.keys(y)[j + T];
             self.indices_mut(z)[j] = self.indices(y)[j + T];
         }
-        if let None = self.children(y) {
-            self.nodes[z as usize].children = 0xFFFFFFFF;
-        } else {
-            self.nodes[z as usize].children = self.children.len() as u32;
-            self.children.push(Default::default());
+        if !leaf {
             for j in 0..T {
                 self.children_mut(z).unwrap()[j] = self.children(y).unwrap()[j + T];
             }
         }
         *self.key_count_mut(y) = (T - 1) as u32;
-        for j in i + 1..*self.key_count(x) as usize + 1 {
-            let j0 = *self.key_count(x) as usize + 1 - j;
-            let array = self.children_mut(x).unwrap();
-            array[j0 + 1] = array[j0];
-        }
+        let count = *self.key_count(x) as usize;
+        self.children_mut(x)
+            .unwrap()
+            .copy_within(i + 1..count + 1, i + 2);
         self.children_mut(x).unwrap()[i + 1] = z;
-        for j in i..*self.key_count(x) as usize {
-            self.keys_mut(x)[j + 1] = self.keys(x)[j];
-            self.indices_mut(x)[j + 1] = self.indices(x)[j];
-        }
+        self.keys_mut(x).copy_within(i..count, i + 1);
+        self.indices_mut(x).copy_within(i..count, i + 1);
         self.keys_mut(x)[i] = self.keys(y)[T - 1];
         self.indices_mut(x)[i] = self.indices(y)[T - 1];
         *self.key_count_mut(x) += 1;
     }
 
     fn keys(&self, node: u32) -> &[K] {
-        &self.nodes[node as usize].keys
+        &self.nodes[node as usize].keys.as_flattened()[..2 * T - 1]
     }
 
     fn keys_mut(&mut self, node: u32) -> &mut [K] {
-        &mut self.nodes[node as usize].keys
+        &mut self.nodes[node as usize].keys.as_flattened_mut()[..2 * T - 1]
     }
 
     fn indices(&self, node: u32) -> &[I] {
-        &self.nodes[node as usize].indices
+        &self.nodes[node as usize].indices.as_flattened()[..2 * T - 1]
     }
 
     fn indices_mut(&mut self, node: u32) -> &mut [I] {
-        &mut self.nodes[node as usize].indices
+        &mut self.nodes[node as usize].indices.as_flattened_mut()[..2 * T - 1]
     }
 
-    fn children(&self, node: u32) -> Option<&[u32; 2 * T]> {
+    fn children(&self, node: u32) -> Option<&[u32]> {
         let node = &self.nodes[node as usize];
-        if node.children == 0xFFFFFFFF {
+        if node.children == LEAF {
             None
         } else {
-            Some(&self.children[node.children as usize])
+            Some(self.children[node.children as usize].as_flattened())
         }
     }
 
-    fn children_mut(&mut self, node: u32) -> Option<&mut [u32; 2 * T]> {
+    fn children_mut(&mut self, node: u32) -> Option<&mut [u32]> {
         let node = &self.nodes[node as usize];
-        if node.children == 0xFFFFFFFF {
+        if node.children == LEAF {
             None
         } else {
-            Some(&mut self.children[node.children as usize])
+            Some(self.children[node.children as usize].as_flattened_mut())
         }
     }
 
@@ -171,31 +832,81 @@ where
         &mut self.nodes[node as usize].key_count
     }
 
-    fn rsearch(&self, node: u32, key: K) -> Option<I> {
-        for (i, &nodekey) in self.keys(node)[..*self.key_count(node) as usize]
-            .iter()
-            .enumerate()
-        {
-            if key == nodekey {
-                return Some(self.indices(node)[i]);
-            } else if key < nodekey {
-                match self.children(node) {
-                    None => return None,
-                    Some(c) => return self.rsearch(c[i as usize], key),
+    // Search for up to `GROUP` keys together. All leaves are at the same depth,
+    // so the searches reach each level, and the leaves, at the same time. Each
+    // level takes two passes over the group, since a node's children are in a
+    // separate array: the first searches each node and prefetches its array
+    // of children, and the second follows the child pointers and prefetches
+    // the nodes for the next level.
+    fn search_group<S>(&self, keys: &[K], indices: &mut [Option<I>])
+    where
+        S: NodeSearch<K>,
+    {
+        let lookup = Lookup {
+            key: K::default(),
+            node: self.root,
+            position: 0,
+            found: None,
+        };
+        let mut lookups = [lookup; GROUP];
+        let lookups = &mut lookups[..keys.len()];
+        for (lookup, &key) in lookups.iter_mut().zip(keys.iter()) {
+            lookup.key = key;
+        }
+
+        loop {
+            for lookup in lookups.iter_mut() {
+                let x = lookup.node;
+                let count = *self.key_count(x) as usize;
+                let i = S::rank(&self.keys(x)[..count], lookup.key);
+                if i < count {
+                    lookup.found = Some((self.keys(x)[i], self.indices(x)[i]));
                 }
+                lookup.position = i;
+                let slot = self.nodes[x as usize].children;
+                if slot != LEAF {
+                    prefetch(&self.children[slot as usize]);
+                }
+            }
+            if self.children(lookups[0].node).is_none() {
+                break;
             }
+            for lookup in lookups.iter_mut() {
+                lookup.node = self.children(lookup.node).unwrap()[lookup.position];
+                prefetch(&self.nodes[lookup.node as usize]);
+            }
+        }
+
+        for (lookup, index) in lookups.iter().zip(indices.iter_mut()) {
+            *index = match lookup.found {
+                Some((k, i)) if k.key_eq(&lookup.key) => Some(i),
+                _ => None,
+            };
         }
-        match self.children(node) {
-            None => None,
-            Some(c) => self.rsearch(c[*self.key_count(node) as usize], key),
+    }
+
+    // The position of the first key in `node` which is not less than `key`.
+    fn find(&self, node: u32, key: K) -> usize {
+        Binary::rank(&self.keys(node)[..*self.key_count(node) as usize], key)
+    }
+
+    // The position of the first key in `node` which is greater than `key`.
+    fn find_after(&self, node: u32, key: K) -> usize {
+        // `find` skips the keys less than `key`, and any equal to it follow
+        let count = *self.key_count(node) as usize;
+        let keys = self.keys(node);
+        let mut i = self.find(node, key);
+        while i < count && keys[i].key_eq(&key) {
+            i += 1;
         }
+        i
     }
 
     fn insert_nonfull(&mut self, x: u32, key: K, index: I) {
         let mut i = *self.key_count(x) as isize - 1;
-        if let None = self.children(x) {
+        if self.children(x).is_none() {
             // x is a leaf
-            while i >= 0 && key < self.keys(x)[i as usize] {
+            while i >= 0 && key.key_lt(&self.keys(x)[i as usize]) {
                 self.keys_mut(x)[(i + 1) as usize] = self.keys(x)[i as usize];
                 self.indices_mut(x)[(i + 1) as usize] = self.indices(x)[i as usize];
                 i -= 1;
@@ -205,13 +916,14 @@ where
             *self.key_count_mut(x) += 1;
         } else {
             // x is internal
-            while i >= 0 && key < self.keys(x)[i as usize] {
+            while i >= 0 && key.key_lt(&self.keys(x)[i as usize]) {
                 i -= 1;
             }
             i += 1;
             if *self.key_count(self.children(x).unwrap()[i as usize]) == 2 * T as u32 - 1 {
                 self.split_child(x, i as usize);
-                if key > self.keys(x)[i as usize] {
+                // equal keys go right, after the ones already present
+                if self.keys(x)[i as usize].key_le(&key) {
                     i += 1;
                 }
             }
@@ -219,22 +931,644 @@ where
             self.insert_nonfull(c, key, index);
         }
     }
+
+    // Remove the key and index at position `i` of `x`, along with the child
+    // to the right of them if `x` is internal.
+    fn remove_from_node(&mut self, x: u32, i: usize) -> (K, I) {
+        let count = *self.key_count(x) as usize;
+        let removed = (self.keys(x)[i], self.indices(x)[i]);
+        self.keys_mut(x).copy_within(i + 1..count, i);
+        self.indices_mut(x).copy_within(i + 1..count, i);
+        if let Some(c) = self.children_mut(x) {
+            c.copy_within(i + 2..count + 1, i + 1);
+        }
+        *self.key_count_mut(x) -= 1;
+        removed
+    }
+
+    // Merge child `i + 1` of `x` and the key separating it from child `i` into
+    // child `i`, freeing child `i + 1`. This is case 2c/3b of Cormen's delete.
+    fn merge_children(&mut self, x: u32, i: usize) {
+        let y = self.children(x).unwrap()[i];
+        let z = self.children(x).unwrap()[i + 1];
+        let y_count = *self.key_count(y) as usize;
+        let z_count = *self.key_count(z) as usize;
+        let (key, index) = self.remove_from_node(x, i);
+        self.keys_mut(y)[y_count] = key;
+        self.indices_mut(y)[y_count] = index;
+        for j in 0..z_count {
+            self.keys_mut(y)[y_count + 1 + j] = self.keys(z)[j];
+            self.indices_mut(y)[y_count + 1 + j] = self.indices(z)[j];
+        }
+        if self.children(y).is_some() {
+            for j in 0..z_count + 1 {
+                self.children_mut(y).unwrap()[y_count + 1 + j] = self.children(z).unwrap()[j];
+            }
+        }
+        *self.key_count_mut(y) = (y_count + 1 + z_count) as u32;
+        self.free_node(z);
+    }
+
+    // Make sure child `i` of `x` has at least `T` keys before we descend into
+    // it, by rotating a key from a sibling or merging with a sibling (case 3
+    // of Cormen's delete). Returns the position the child ends up in.
+    fn fill_child(&mut self, x: u32, i: usize) -> usize {
+        let count = *self.key_count(x) as usize;
+        let c = self.children(x).unwrap()[i];
+        let c_count = *self.key_count(c) as usize;
+        if c_count >= T {
+            return i;
+        }
+
+        if i > 0 && *self.key_count(self.children(x).unwrap()[i - 1]) as usize >= T {
+            // rotate right through the separator keys[i - 1]
+            let left = self.children(x).unwrap()[i - 1];
+            let left_count = *self.key_count(left) as usize;
+            self.keys_mut(c).copy_within(0..c_count, 1);
+            self.indices_mut(c).copy_within(0..c_count, 1);
+            self.keys_mut(c)[0] = self.keys(x)[i - 1];
+            self.indices_mut(c)[0] = self.indices(x)[i - 1];
+            self.keys_mut(x)[i - 1] = self.keys(left)[left_count - 1];
+            self.indices_mut(x)[i - 1] = self.indices(left)[left_count - 1];
+            if self.children(c).is_some() {
+                let moved = self.children(left).unwrap()[left_count];
+                let array = self.children_mut(c).unwrap();
+                array.copy_within(0..c_count + 1, 1);
+                array[0] = moved;
+            }
+            *self.key_count_mut(left) -= 1;
+            *self.key_count_mut(c) += 1;
+            i
+        } else if i < count && *self.key_count(self.children(x).unwrap()[i + 1]) as usize >= T {
+            // rotate left through the separator keys[i]
+            let right = self.children(x).unwrap()[i + 1];
+            let right_count = *self.key_count(right) as usize;
+            self.keys_mut(c)[c_count] = self.keys(x)[i];
+            self.indices_mut(c)[c_count] = self.indices(x)[i];
+            self.keys_mut(x)[i] = self.keys(right)[0];
+            self.indices_mut(x)[i] = self.indices(right)[0];
+            self.keys_mut(right).copy_within(1..right_count, 0);
+            self.indices_mut(right).copy_within(1..right_count, 0);
+            if self.children(c).is_some() {
+                let moved = self.children(right).unwrap()[0];
+                self.children_mut(c).unwrap()[c_count + 1] = moved;
+                self.children_mut(right)
+                    .unwrap()
+                    .copy_within(1..right_count + 1, 0);
+            }
+            *self.key_count_mut(right) -= 1;
+            *self.key_count_mut(c) += 1;
+            i
+        } else if i < count {
+            self.merge_children(x, i);
+            i
+        } else {
+            self.merge_children(x, i - 1);
+            i - 1
+        }
+    }
+
+    // Remove and return the largest key in the subtree rooted at `x`, which
+    // must have at least `T` keys unless it is the root.
+    fn remove_max(&mut self, x: u32) -> (K, I) {
+        let count = *self.key_count(x) as usize;
+        if self.children(x).is_none() {
+            self.remove_from_node(x, count - 1)
+        } else {
+            let i = self.fill_child(x, count);
+            let c = self.children(x).unwrap()[i];
+            self.remove_max(c)
+        }
+    }
+
+    // Remove and return the smallest key in the subtree rooted at `x`, which
+    // must have at least `T` keys unless it is the root.
+    fn remove_min(&mut self, x: u32) -> (K, I) {
+        if self.children(x).is_none() {
+            self.remove_from_node(x, 0)
+        } else {
+            let i = self.fill_child(x, 0);
+            let c = self.children(x).unwrap()[i];
+            self.remove_min(c)
+        }
+    }
+
+    fn rremove(&mut self, x: u32, key: K) -> Option<I> {
+        let count = *self.key_count(x) as usize;
+        let i = self.find(x, key);
+        let found = i < count && self.keys(x)[i].key_eq(&key);
+        if self.children(x).is_none() {
+            // case 1: x is a leaf
+            return if found {
+                Some(self.remove_from_node(x, i).1)
+            } else {
+                None
+            };
+        }
+
+        // case 3: descend, making sure the child can afford to lose a key.
+        // Even if x holds key, an earlier entry with the same key may be in
+        // the child before it, so that child is searched first. If `fill_child`
+        // moves the entry in x down into the child, it's found there.
+        let i = self.fill_child(x, i);
+        let c = self.children(x).unwrap()[i];
+        match self.rremove(c, key) {
+            None if found => Some(self.remove_at(x, i)),
+            result => result,
+        }
+    }
+
+    // Remove entry `i` of `x`, which must have at least `T` keys unless it is
+    // the root, and return its index. For an internal node this is case 2 of
+    // Cormen's delete.
+    fn remove_at(&mut self, x: u32, i: usize) -> I {
+        if self.children(x).is_none() {
+            return self.remove_from_node(x, i).1;
+        }
+        let y = self.children(x).unwrap()[i];
+        let z = self.children(x).unwrap()[i + 1];
+        let index = self.indices(x)[i];
+        let y_count = *self.key_count(y) as usize;
+        if y_count >= T {
+            let (k, idx) = self.remove_max(y);
+            self.keys_mut(x)[i] = k;
+            self.indices_mut(x)[i] = idx;
+            index
+        } else if *self.key_count(z) as usize >= T {
+            let (k, idx) = self.remove_min(z);
+            self.keys_mut(x)[i] = k;
+            self.indices_mut(x)[i] = idx;
+            index
+        } else {
+            // the entry ends up between the keys of y and those of z
+            self.merge_children(x, i);
+            self.remove_at(y, y_count)
+        }
+    }
+}
+
+// State shared by the recursive calls of `BTree::validate_node`.
+struct ValidationContext<'a> {
+    // nodes which are on the free list or have been reached from the root
+    seen: &'a mut [bool],
+
+    // the same for slots in `BTree::children`
+    children_seen: &'a mut [bool],
+
+    leaf_depth: &'a mut Option<usize>,
+
+    // keys in the nodes visited so far
+    key_count: usize,
+}
+
+// The state of one search in `BTree::search_group`.
+#[derive(Copy, Clone)]
+struct Lookup<K, I> {
+    key: K,
+
+    // the node to search at the current level, and the position found in it
+    node: u32,
+    position: usize,
+
+    // the last entry found that isn't less than `key`
+    found: Option<(K, I)>,
+}
+
+/// A problem found by `BTree::validate`.
+///
+/// Nodes are identified by their position in the tree's internal storage.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ValidationError {
+    /// A node has too few or too many keys.
+    KeyCount { node: u32, count: usize },
+
+    /// A key is less than the one before it in its node, or lies outside the
+    /// range given by the keys of the node's ancestors.
+    KeyOrder { node: u32, position: usize },
+
+    /// A leaf is at a different depth than the first leaf.
+    LeafDepth {
+        node: u32,
+        depth: usize,
+        expected: usize,
+    },
+
+    /// A node's array of children is missing or shared with another node.
+    BadChildren { node: u32, children: u32 },
+
+    /// A child link points to a missing node or one that is already in use.
+    BadChild {
+        node: u32,
+        position: usize,
+        child: u32,
+    },
+
+    /// The free list holds a missing node, or the same node twice.
+    BadFreeNode { node: u32 },
+
+    /// The free list of children arrays holds a missing slot, or the same
+    /// slot twice.
+    BadFreeChildren { children: u32 },
+
+    /// A node is neither reachable from the root nor on the free list.
+    Unreachable { node: u32 },
+
+    /// The tree's count of its entries is wrong.
+    Len { len: usize, count: usize },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ValidationError::*;
+
+        match *self {
+            KeyCount { node, count } => write!(f, "node {} has {} keys", node, count),
+            KeyOrder { node, position } => {
+                write!(f, "key {} of node {} is out of order", position, node)
+            }
+            LeafDepth {
+                node,
+                depth,
+                expected,
+            } => write!(
+                f,
+                "leaf {} is at depth {} rather than {}",
+                node, depth, expected
+            ),
+            BadChildren { node, children } => {
+                write!(f, "node {} has invalid children array {}", node, children)
+            }
+            BadChild {
+                node,
+                position,
+                child,
+            } => write!(
+                f,
+                "child {} of node {} is invalid node {}",
+                position, node, child
+            ),
+            BadFreeNode { node } => write!(f, "invalid node {} on the free list", node),
+            BadFreeChildren { children } => {
+                write!(f, "invalid children array {} on the free list", children)
+            }
+            Unreachable { node } => write!(f, "node {} is unreachable", node),
+            Len { len, count } => write!(f, "the tree counts {} entries but holds {}", len, count),
+        }
+    }
+}
+
+impl Error for ValidationError {}
+
+/// The shape and size of a `BTree`, as reported by `BTree::stats`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Stats {
+    /// The number of levels, counting the root and the leaves.
+    pub height: usize,
+
+    /// The number of nodes reachable from the root.
+    pub node_count: usize,
+
+    pub key_count: usize,
+
+    /// The fraction of the key slots in the nodes that are in use.
+    pub fill_factor: f64,
+
+    /// The memory used by the tree, including unused capacity, free nodes and
+    /// nodes shared with snapshots.
+    pub bytes_used: usize,
 }
 
-impl<K, I> Model<K, I> for BTree<K, I>
+impl<K, I, const T: usize> Model<K, I> for BTree<K, I, T>
 where
-    K: Copy + Default + PartialEq + PartialOrd,
+    K: Key,
     I: Copy + Default,
 {
     fn eval(&self, key: K) -> Option<I> {
         self.search(key)
     }
-}
 
-#[cfg(test)]
-mod tests {
+    fn eval_lower_bound(&self, key: K) -> Option<I> {
+        self.lower_bound(key).map(|(_, index)| index)
+    }
+
+    fn eval_upper_bound(&self, key: K) -> Option<I> {
+        self.upper_bound(key).map(|(_, index)| index)
+    }
+
+    fn eval_many(&self, keys: &[K], indices: &mut [Option<I>]) {
+        self.search_many(keys, indices)
+    }
+}
+
+/// The contents of a `BTree` at one point in time, created by
+/// `BTree::snapshot`.
+///
+/// A snapshot owns its nodes, shared with the tree until the tree changes
+/// them, so it can outlive the tree or be sent to another thread while the
+/// tree keeps changing.
+#[derive(Clone, Debug)]
+pub struct Snapshot<K, I, const T: usize = 8> {
+    // never changed, and without free lists
+    tree: BTree<K, I, T>,
+}
+
+impl<K, I, const T: usize> Snapshot<K, I, T>
+where
+    K: Key,
+    I: Copy + Default,
+{
+    /// See `BTree::len`.
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// See `BTree::is_empty`.
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// See `BTree::contains_key`.
+    pub fn contains_key(&self, key: K) -> bool {
+        self.tree.contains_key(key)
+    }
+
+    /// See `BTree::first`.
+    pub fn first(&self) -> Option<(K, I)> {
+        self.tree.first()
+    }
+
+    /// See `BTree::last`.
+    pub fn last(&self) -> Option<(K, I)> {
+        self.tree.last()
+    }
+
+    /// See `BTree::search`.
+    pub fn search(&self, key: K) -> Option<I> {
+        self.tree.search(key)
+    }
+
+    /// See `BTree::search_all`.
+    pub fn search_all(&self, key: K) -> impl Iterator<Item = I> + '_ {
+        self.tree.search_all(key)
+    }
+
+    /// See `BTree::lower_bound`.
+    pub fn lower_bound(&self, key: K) -> Option<(K, I)> {
+        self.tree.lower_bound(key)
+    }
+
+    /// See `BTree::upper_bound`.
+    pub fn upper_bound(&self, key: K) -> Option<(K, I)> {
+        self.tree.upper_bound(key)
+    }
+
+    /// See `BTree::predecessor`.
+    pub fn predecessor(&self, key: K) -> Option<(K, I)> {
+        self.tree.predecessor(key)
+    }
+
+    /// See `BTree::iter`.
+    pub fn iter(&self) -> Iter<'_, K, I, T> {
+        self.tree.iter()
+    }
+
+    /// See `BTree::range`.
+    pub fn range<R>(&self, range: R) -> Range<'_, K, I, T>
+    where
+        R: RangeBounds<K>,
+    {
+        self.tree.range(range)
+    }
+}
+
+impl<K, I, const T: usize> Model<K, I> for Snapshot<K, I, T>
+where
+    K: Key,
+    I: Copy + Default,
+{
+    fn eval(&self, key: K) -> Option<I> {
+        self.tree.eval(key)
+    }
+
+    fn eval_lower_bound(&self, key: K) -> Option<I> {
+        self.tree.eval_lower_bound(key)
+    }
+
+    fn eval_upper_bound(&self, key: K) -> Option<I> {
+        self.tree.eval_upper_bound(key)
+    }
+
+    fn eval_many(&self, keys: &[K], indices: &mut [Option<I>]) {
+        self.tree.search_many(keys, indices)
+    }
+}
+
+/// A `BTree` which uses `S` to search within nodes.
+///
+/// Created by `BTree::with_node_search`.
+#[derive(Debug)]
+pub struct WithNodeSearch<'a, K: 'a, I: 'a, const T: usize, S> {
+    tree: &'a BTree<K, I, T>,
+    phantom: PhantomData<S>,
+}
+
+impl<'a, K, I, const T: usize, S> Model<K, I> for WithNodeSearch<'a, K, I, T, S>
+where
+    K: Key,
+    I: Copy + Default,
+    S: NodeSearch<K>,
+{
+    fn eval(&self, key: K) -> Option<I> {
+        self.tree.search_with::<S>(key)
+    }
+
+    fn eval_lower_bound(&self, key: K) -> Option<I> {
+        self.tree.lower_bound_with::<S>(key).map(|(_, index)| index)
+    }
+
+    fn eval_upper_bound(&self, key: K) -> Option<I> {
+        self.tree.upper_bound_with::<S>(key).map(|(_, index)| index)
+    }
+
+    fn eval_many(&self, keys: &[K], indices: &mut [Option<I>]) {
+        self.tree.search_many_with::<S>(keys, indices)
+    }
+}
+
+impl<'a, K, I, const T: usize> IntoIterator for &'a BTree<K, I, T>
+where
+    K: Key,
+    I: Copy + Default,
+{
+    type Item = (K, I);
+    type IntoIter = Iter<'a, K, I, T>;
+
+    fn into_iter(self) -> Iter<'a, K, I, T> {
+        self.iter()
+    }
+}
+
+/// An iterator over the entries of a `BTree` whose keys lie in some range.
+///
+/// Created by `BTree::range`.
+#[derive(Clone, Debug)]
+pub struct Range<'a, K: 'a, I: 'a, const T: usize = 8> {
+    tree: &'a BTree<K, I, T>,
+
+    // The path from the root to the node holding the next key. Each item is a
+    // node and the position of the next key to visit in it; everything in the
+    // subtrees to the left of that position has already been visited.
+    stack: Vec<(u32, usize)>,
+
+    end: Bound<K>,
+}
+
+impl<'a, K, I, const T: usize> Iterator for Range<'a, K, I, T>
+where
+    K: Key,
+    I: Copy + Default,
+{
+    type Item = (K, I);
+
+    fn next(&mut self) -> Option<(K, I)> {
+        self.tree.range_next(&mut self.stack, self.end)
+    }
+}
+
+/// An iterator over all entries of a `BTree`, in key order.
+///
+/// Created by `BTree::iter`.
+#[derive(Clone, Debug)]
+pub struct Iter<'a, K: 'a, I: 'a, const T: usize = 8> {
+    range: Range<'a, K, I, T>,
+
+    // the number of entries not yet visited
+    remaining: usize,
+}
+
+impl<'a, K, I, const T: usize> Iterator for Iter<'a, K, I, T>
+where
+    K: Key,
+    I: Copy + Default,
+{
+    type Item = (K, I);
+
+    fn next(&mut self) -> Option<(K, I)> {
+        let result = self.range.next();
+        if result.is_some() {
+            self.remaining -= 1;
+        }
+        result
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K, I, const T: usize> ExactSizeIterator for Iter<'a, K, I, T>
+where
+    K: Key,
+    I: Copy + Default,
+{
+}
+
+impl<K, I, const T: usize> IntoIterator for BTree<K, I, T>
+where
+    K: Key,
+    I: Copy + Default,
+{
+    type Item = (K, I);
+    type IntoIter = IntoIter<K, I, T>;
+
+    fn into_iter(self) -> IntoIter<K, I, T> {
+        IntoIter {
+            stack: self.range_start(Bound::Unbounded),
+            tree: self,
+        }
+    }
+}
+
+/// An iterator over all entries of a `BTree`, in key order, which takes
+/// ownership of the tree.
+///
+/// Created by `BTree::into_iter`.
+#[derive(Clone, Debug)]
+pub struct IntoIter<K, I, const T: usize = 8> {
+    tree: BTree<K, I, T>,
+
+    // as in `Range`
+    stack: Vec<(u32, usize)>,
+}
+
+impl<K, I, const T: usize> Iterator for IntoIter<K, I, T>
+where
+    K: Key,
+    I: Copy + Default,
+{
+    type Item = (K, I);
+
+    fn next(&mut self) -> Option<(K, I)> {
+        let result = self.tree.range_next(&mut self.stack, Bound::Unbounded);
+        if result.is_some() {
+            self.tree.len -= 1;
+        }
+        result
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.tree.len, Some(self.tree.len))
+    }
+}
+
+impl<K, I, const T: usize> ExactSizeIterator for IntoIter<K, I, T>
+where
+    K: Key,
+    I: Copy + Default,
+{
+}
+
+/// Build a tree by inserting the pairs one at a time. If they are already
+/// sorted, `BTree::from_sorted` is faster and gives a shallower tree.
+impl<K, I, const T: usize> FromIterator<(K, I)> for BTree<K, I, T>
+where
+    K: Key,
+    I: Copy + Default,
+{
+    fn from_iter<It>(iter: It) -> Self
+    where
+        It: IntoIterator<Item = (K, I)>,
+    {
+        let mut tree = Self::new();
+        tree.extend(iter);
+        tree
+    }
+}
+
+impl<K, I, const T: usize> Extend<(K, I)> for BTree<K, I, T>
+where
+    K: Key,
+    I: Copy + Default,
+{
+    fn extend<It>(&mut self, iter: It)
+    where
+        It: IntoIterator<Item = (K, I)>,
+    {
+        for (key, index) in iter {
+            self.insert(key, index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
     use super::*;
 
+    use std::collections::btree_map::Entry;
+    use std::collections::BTreeMap;
+
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
     #[test]
     fn t() {
         let mut b: BTree<f32, u32> = Default::default();
@@ -245,4 +1579,529 @@ mod tests {
             assert_eq!(b.search(i as f32).unwrap(), i as u32);
         }
     }
+
+    #[test]
+    fn iter() {
+        let mut rng = XorShiftRng::from_seed([3; 16]);
+        let mut b: BTree<u32, u32> = BTree::new();
+        let mut reference = BTreeMap::new();
+        for i in 0..3000 {
+            let key = rng.gen_range(0, 100000);
+            if let Entry::Vacant(e) = reference.entry(key) {
+                b.insert(key, i);
+                e.insert(i);
+            }
+        }
+        let expected: Vec<(u32, u32)> = reference.iter().map(|(&k, &i)| (k, i)).collect();
+        assert_eq!(b.iter().collect::<Vec<_>>(), expected);
+        assert_eq!((&b).into_iter().count(), expected.len());
+        assert_eq!(BTree::<u32, u32>::new().iter().next(), None);
+    }
+
+    #[test]
+    fn range() {
+        let mut b: BTree<u32, u32> = BTree::new();
+        for i in 0..1000 {
+            b.insert(2 * i, i);
+        }
+        let keys = |v: Vec<(u32, u32)>| v.into_iter().map(|p| p.0).collect::<Vec<_>>();
+        assert_eq!(keys(b.range(10..20).collect()), vec![10, 12, 14, 16, 18]);
+        assert_eq!(keys(b.range(11..=20).collect()), vec![12, 14, 16, 18, 20]);
+        assert_eq!(
+            keys(b.range(1990..).collect()),
+            vec![1990, 1992, 1994, 1996, 1998]
+        );
+        assert_eq!(keys(b.range(..5).collect()), vec![0, 2, 4]);
+        assert_eq!(
+            keys(
+                b.range((Bound::Excluded(10), Bound::Excluded(16)))
+                    .collect()
+            ),
+            vec![12, 14]
+        );
+        assert_eq!(b.range(20..20).count(), 0);
+        assert_eq!(b.range(3000..4000).count(), 0);
+        for lo in 0..100 {
+            for hi in lo..100 {
+                let expected = (lo * 17..hi * 17).filter(|k| k % 2 == 0 && *k < 2000);
+                assert!(b.range(lo * 17..hi * 17).map(|p| p.0).eq(expected));
+            }
+        }
+    }
+
+    #[test]
+    fn from_sorted() {
+        for &n in &[
+            0, 1, 2, 14, 15, 16, 17, 31, 32, 33, 255, 256, 257, 1000, 4097, 20000,
+        ] {
+            let pairs: Vec<(u32, u32)> = (0..n).map(|i| (3 * i, i)).collect();
+            let mut b: BTree<u32, u32> = BTree::from_sorted(&pairs);
+            b.validate().unwrap();
+            assert_eq!(b.iter().collect::<Vec<_>>(), pairs);
+            for &(key, index) in pairs.iter() {
+                assert_eq!(b.search(key), Some(index));
+                assert_eq!(b.search(key + 1), None);
+            }
+
+            // the tree should remain usable for updates
+            b.insert(1, 1000000);
+            assert_eq!(b.search(1), Some(1000000));
+            for &(key, index) in pairs.iter() {
+                assert_eq!(b.remove(key), Some(index));
+            }
+            assert_eq!(b.iter().collect::<Vec<_>>(), vec![(1, 1000000)]);
+        }
+    }
+
+    #[test]
+    fn from_sorted_parts() {
+        let keys: Vec<f32> = (0..5000).map(|i| i as f32 / 7.0).collect();
+        let indices: Vec<u32> = (0..5000).collect();
+        let b: BTree<f32, u32> = BTree::from_sorted_parts(&keys, &indices);
+        b.validate().unwrap();
+        for (&key, &index) in keys.iter().zip(indices.iter()) {
+            assert_eq!(b.search(key), Some(index));
+        }
+        // the nodes should be nearly full
+        assert!(b.nodes.len() <= 5000 / 14 + 1);
+    }
+
+    #[test]
+    fn bounds() {
+        let mut rng = XorShiftRng::from_seed([5; 16]);
+        let mut b: BTree<u32, u32> = BTree::new();
+        let mut reference = BTreeMap::new();
+        for i in 0..2000 {
+            let key = 3 * rng.gen_range(0, 2000);
+            if let Entry::Vacant(e) = reference.entry(key) {
+                b.insert(key, i);
+                e.insert(i);
+            }
+        }
+        let pair = |p: Option<(&u32, &u32)>| p.map(|(&k, &i)| (k, i));
+        for key in 0..6010 {
+            let lower = pair(reference.range(key..).next());
+            let upper = pair(reference.range(key + 1..).next());
+            let predecessor = pair(reference.range(..key).next_back());
+            assert_eq!(b.lower_bound(key), lower);
+            assert_eq!(b.upper_bound(key), upper);
+            assert_eq!(b.successor(key), upper);
+            assert_eq!(b.predecessor(key), predecessor);
+            assert_eq!(b.eval_lower_bound(key), lower.map(|p| p.1));
+            assert_eq!(b.eval_upper_bound(key), upper.map(|p| p.1));
+        }
+
+        let empty: BTree<u32, u32> = BTree::new();
+        assert_eq!(empty.lower_bound(0), None);
+        assert_eq!(empty.predecessor(0), None);
+    }
+
+    #[test]
+    fn duplicates() {
+        let mut rng = XorShiftRng::from_seed([9; 16]);
+        let mut b: BTree<u32, u32> = BTree::new();
+        let mut reference: Vec<(u32, u32)> = Vec::new();
+        for i in 0..5000 {
+            // plenty of copies of a few keys, so duplicates span many nodes
+            let key = if rng.gen::<bool>() {
+                rng.gen_range(0, 5)
+            } else {
+                rng.gen_range(0, 1000)
+            };
+            b.insert(key, i);
+            reference.push((key, i));
+        }
+        reference.sort_by_key(|p| p.0);
+        assert_eq!(b.iter().collect::<Vec<_>>(), reference);
+        for key in 0..1001 {
+            let expected: Vec<u32> = reference
+                .iter()
+                .filter(|p| p.0 == key)
+                .map(|p| p.1)
+                .collect();
+            assert_eq!(b.search_all(key).collect::<Vec<_>>(), expected);
+            assert_eq!(b.equal_range(key).count(), expected.len());
+            assert_eq!(b.search(key), expected.first().cloned());
+        }
+
+        // removal takes the earliest inserted entry first
+        for _ in 0..1000 {
+            let key = rng.gen_range(0, 5);
+            let position = reference.iter().position(|p| p.0 == key);
+            let expected = position.map(|i| reference.remove(i).1);
+            assert_eq!(b.remove(key), expected);
+        }
+        assert_eq!(b.iter().collect::<Vec<_>>(), reference);
+        b.validate().unwrap();
+
+        let pairs: Vec<(u32, u32)> = (0..1000).map(|i| (i / 100, i)).collect();
+        let b: BTree<u32, u32> = BTree::from_sorted(&pairs);
+        for key in 0..10 {
+            assert!(b.search_all(key).eq(key * 100..key * 100 + 100));
+        }
+    }
+
+    #[test]
+    fn float_keys() {
+        use std::f32;
+
+        let special = [f32::NAN, -f32::NAN, -0.0, 0.0, f32::INFINITY];
+        let mut b: BTree<f32, u32, 2> = BTree::new();
+        for i in 0..500 {
+            let key = if i % 5 == 0 {
+                special[(i / 5) % special.len()]
+            } else {
+                (i % 37) as f32 - 18.0
+            };
+            b.insert(key, i as u32);
+        }
+        b.validate().unwrap();
+        assert_eq!(b.search(f32::NAN), Some(0));
+        assert_eq!(b.search(-f32::NAN), Some(5));
+        assert_eq!(b.search(-0.0), Some(10));
+        assert_eq!(b.search(0.0), Some(15));
+        assert_eq!(b.search(f32::INFINITY), Some(20));
+        let keys: Vec<f32> = b.iter().map(|p| p.0).collect();
+        assert!(keys.windows(2).all(|w| w[0].key_le(&w[1])));
+        assert!(keys[0].is_nan() && keys[keys.len() - 1].is_nan());
+        for i in 0..20 {
+            assert_eq!(b.remove(f32::NAN), Some(25 * i));
+        }
+        assert_eq!(b.remove(f32::NAN), None);
+        assert_eq!(b.search(-f32::NAN), Some(5));
+        b.validate().unwrap();
+    }
+
+    #[test]
+    fn node_search_strategies() {
+        use node_search::{Linear, Simd};
+
+        let mut rng = XorShiftRng::from_seed([11; 16]);
+        let mut b: BTree<f32, u32> = BTree::new();
+        for i in 0..3000 {
+            b.insert(rng.gen_range(0, 1000) as f32, i);
+        }
+        for key in 0..1001 {
+            let key = key as f32 - 0.5 * (key % 2) as f32;
+            let expected = b.search(key);
+            assert_eq!(b.search_with::<Linear>(key), expected);
+            assert_eq!(b.search_with::<Simd>(key), expected);
+            assert_eq!(b.with_node_search::<Simd>().eval(key), expected);
+            assert_eq!(b.lower_bound_with::<Linear>(key), b.lower_bound(key));
+            assert_eq!(b.lower_bound_with::<Simd>(key), b.lower_bound(key));
+            let upper = b.iter().find(|&(k, _)| k > key);
+            assert_eq!(b.upper_bound(key), upper);
+            assert_eq!(b.upper_bound_with::<Linear>(key), upper);
+            assert_eq!(b.upper_bound_with::<Simd>(key), upper);
+            let simd = b.with_node_search::<Simd>();
+            assert_eq!(simd.eval_upper_bound(key), upper.map(|(_, index)| index));
+        }
+    }
+
+    #[test]
+    fn validate() {
+        let mut b: BTree<u32, u32> = BTree::new();
+        assert_eq!(b.validate(), Ok(()));
+        for i in 0..1000 {
+            b.insert(i, i);
+        }
+        assert_eq!(b.validate(), Ok(()));
+
+        let root = b.root;
+        let mut broken = b.clone();
+        broken.keys_mut(root).swap(0, 1);
+        assert_eq!(
+            broken.validate(),
+            Err(ValidationError::KeyOrder {
+                node: root,
+                position: 1
+            })
+        );
+
+        let mut broken = b.clone();
+        let leaf = broken.children(root).unwrap()[0];
+        *broken.key_count_mut(leaf) = 1;
+        assert_eq!(
+            broken.validate(),
+            Err(ValidationError::KeyCount {
+                node: leaf,
+                count: 1
+            })
+        );
+
+        let mut broken = b.clone();
+        let c = broken.children(root).unwrap()[0];
+        broken.children_mut(root).unwrap()[1] = c;
+        match broken.validate() {
+            Err(ValidationError::BadChild { node, .. }) => assert_eq!(node, root),
+            result => panic!("unexpected {:?}", result),
+        }
+
+        let mut broken = b.clone();
+        broken.free_nodes.push(root);
+        assert_eq!(
+            broken.validate(),
+            Err(ValidationError::BadFreeNode { node: root })
+        );
+    }
+
+    #[test]
+    fn stats() {
+        let b: BTree<u32, u32> = BTree::new();
+        let stats = b.stats();
+        assert_eq!((stats.height, stats.node_count, stats.key_count), (1, 1, 0));
+
+        let pairs: Vec<(u32, u32)> = (0..10000).map(|i| (i, i)).collect();
+        let b: BTree<u32, u32> = BTree::from_sorted(&pairs);
+        let stats = b.stats();
+        assert_eq!(stats.key_count, 10000);
+        assert_eq!(stats.height, 4);
+        assert!(stats.fill_factor > 0.9 && stats.fill_factor <= 1.0);
+        assert!(stats.bytes_used >= stats.node_count * 15 * 8);
+
+        let mut b: BTree<u32, u32> = BTree::new();
+        for &(key, index) in pairs.iter() {
+            b.insert(key, index);
+        }
+        let stats = b.stats();
+        assert_eq!(stats.key_count, 10000);
+        assert!(stats.fill_factor < 0.6);
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut rng = XorShiftRng::from_seed([15; 16]);
+        let mut b: BTree<u32, u32, 3> = BTree::new();
+        let mut snapshots = Vec::new();
+        for round in 0..10 {
+            for i in 0..1000 {
+                b.insert(rng.gen_range(0, 5000), 1000 * round + i);
+            }
+            for _ in 0..300 {
+                b.remove(rng.gen_range(0, 5000));
+            }
+            snapshots.push((b.snapshot(), b.iter().collect::<Vec<_>>()));
+        }
+        b.validate().unwrap();
+        for (snapshot, contents) in snapshots.iter() {
+            assert_eq!(snapshot.iter().collect::<Vec<_>>(), *contents);
+            for key in 0..5001 {
+                let expected = contents.iter().find(|p| p.0 >= key).cloned();
+                assert_eq!(snapshot.lower_bound(key), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn snapshot_copies_touched_nodes() {
+        use arena::CHUNK;
+
+        let pairs: Vec<(u32, u32)> = (0..100000).map(|i| (2 * i, i)).collect();
+        let mut b: BTree<u32, u32> = BTree::from_sorted(&pairs);
+        let snapshot = b.snapshot();
+        let chunks = snapshot.tree.nodes.capacity() / CHUNK;
+        assert_eq!(b.nodes.shared_chunks(&snapshot.tree.nodes), chunks);
+
+        // The nodes are full, so this splits a node on every level. It changes
+        // the nodes on the path to the leaf, and adds new ones at the end of
+        // the arena.
+        b.insert(1, 0);
+        let height = snapshot.tree.stats().height;
+        assert!(b.nodes.shared_chunks(&snapshot.tree.nodes) >= chunks - height - 1);
+        assert_eq!(snapshot.search(1), None);
+        assert_eq!(b.search(1), Some(0));
+        assert!(snapshot.iter().eq(pairs.iter().cloned()));
+    }
+
+    #[test]
+    fn snapshot_across_threads() {
+        use std::thread;
+
+        let mut b: BTree<u32, u32> = BTree::new();
+        for i in 0..20000 {
+            b.insert(i, i);
+        }
+        let snapshot = b.snapshot();
+        let reader = thread::spawn(move || {
+            for _ in 0..5 {
+                assert!(snapshot.iter().eq((0..20000).map(|i| (i, i))));
+            }
+        });
+        for i in 0..20000 {
+            b.remove(i);
+            b.insert(i + 20000, i);
+        }
+        reader.join().unwrap();
+        assert!(b.iter().eq((0..20000).map(|i| (i + 20000, i))));
+    }
+
+    #[test]
+    fn remove() {
+        let mut b: BTree<u32, u32> = BTree::new();
+        for i in 0..500 {
+            b.insert(i, 2 * i);
+        }
+        for i in (0..500).filter(|i| i % 3 == 0) {
+            assert_eq!(b.remove(i), Some(2 * i));
+            assert_eq!(b.remove(i), None);
+        }
+        for i in 0..500 {
+            let expected = if i % 3 == 0 { None } else { Some(2 * i) };
+            assert_eq!(b.search(i), expected);
+        }
+        b.validate().unwrap();
+        for i in 0..500 {
+            b.remove(i);
+        }
+        assert_eq!(*b.key_count(b.root), 0);
+        assert!(b.children(b.root).is_none());
+    }
+
+    #[test]
+    fn remove_reuses_slots() {
+        let mut b: BTree<u32, u32> = BTree::new();
+        for i in 0..1000 {
+            b.insert(i, i);
+        }
+        let nodes = b.nodes.len();
+        let children = b.children.len();
+        for i in 0..1000 {
+            b.remove(i);
+        }
+        for i in 0..1000 {
+            b.insert(i, i);
+        }
+        assert_eq!(b.nodes.len(), nodes);
+        assert_eq!(b.children.len(), children);
+    }
+
+    #[test]
+    fn interleaved_against_reference() {
+        interleaved_against_reference_degree::<8>();
+    }
+
+    #[test]
+    fn minimum_degrees() {
+        interleaved_against_reference_degree::<2>();
+        interleaved_against_reference_degree::<3>();
+        interleaved_against_reference_degree::<4>();
+        interleaved_against_reference_degree::<32>();
+        interleaved_against_reference_degree::<128>();
+
+        let pairs: Vec<(u32, u32)> = (0..3000).map(|i| (i, i)).collect();
+        let b: BTree<u32, u32, 2> = BTree::from_sorted(&pairs);
+        b.validate().unwrap();
+        assert!(b.iter().eq(pairs.iter().cloned()));
+        let b: BTree<u32, u32, 64> = BTree::from_sorted(&pairs);
+        b.validate().unwrap();
+        assert!(b.iter().eq(pairs.iter().cloned()));
+    }
+
+    fn interleaved_against_reference_degree<const T: usize>() {
+        let mut rng = XorShiftRng::from_seed([7; 16]);
+        let mut b: BTree<u32, u32, T> = BTree::new();
+        let mut reference: BTreeMap<u32, u32> = BTreeMap::new();
+        for round in 0..20000u32 {
+            let key = rng.gen_range(0, 2000);
+            if rng.gen::<bool>() {
+                assert_eq!(b.remove(key), reference.remove(&key));
+            } else if let Entry::Vacant(e) = reference.entry(key) {
+                b.insert(key, round);
+                e.insert(round);
+            }
+        }
+        for key in 0..2000 {
+            assert_eq!(b.search(key), reference.get(&key).cloned());
+        }
+        b.validate().unwrap();
+        assert_eq!(b.len(), reference.len());
+        for (&key, &index) in reference.iter() {
+            assert_eq!(b.remove(key), Some(index));
+        }
+        for key in 0..2000 {
+            assert_eq!(b.search(key), None);
+        }
+        assert!(b.is_empty());
+    }
+
+    fn search_many_degree<const T: usize>(n: u32, rng: &mut XorShiftRng) {
+        use node_search::Simd;
+
+        let mut b: BTree<f32, u32, T> = (0..n).map(|i| (rng.gen_range(0, n) as f32, i)).collect();
+        for _ in 0..n / 4 {
+            b.remove(rng.gen_range(0, n) as f32);
+        }
+        let keys: Vec<f32> = (0..n + 37)
+            .map(|_| rng.gen_range(0, 2 * n + 1) as f32 / 2.0)
+            .collect();
+        let expected: Vec<Option<u32>> = keys.iter().map(|&k| b.search(k)).collect();
+
+        let mut indices = vec![None; keys.len()];
+        b.eval_many(&keys, &mut indices);
+        assert_eq!(indices, expected);
+
+        let mut indices = vec![None; keys.len()];
+        b.with_node_search::<Simd>().eval_many(&keys, &mut indices);
+        assert_eq!(indices, expected);
+
+        let mut indices = vec![None; keys.len()];
+        b.snapshot().eval_many(&keys, &mut indices);
+        assert_eq!(indices, expected);
+    }
+
+    #[test]
+    fn search_many() {
+        let mut rng = XorShiftRng::from_seed([17; 16]);
+        for &n in [0, 1, 5, 16, 100, 10000].iter() {
+            search_many_degree::<2>(n, &mut rng);
+            search_many_degree::<8>(n, &mut rng);
+            search_many_degree::<64>(n, &mut rng);
+        }
+    }
+
+    #[test]
+    fn collection() {
+        let empty: BTree<u32, u32, 2> = BTree::new();
+        assert_eq!((empty.len(), empty.is_empty()), (0, true));
+        assert_eq!((empty.first(), empty.last()), (None, None));
+        assert_eq!(empty.into_iter().next(), None);
+
+        let pairs: Vec<(u32, u32)> = (0..1000).map(|i| ((i * 7919) % 500, i)).collect();
+        let mut b: BTree<u32, u32, 2> = pairs.iter().cloned().collect();
+        b.validate().unwrap();
+        assert_eq!((b.len(), b.is_empty()), (1000, false));
+        assert_eq!(b.first(), Some((0, 0)));
+        assert_eq!(b.last(), Some((499, 821)));
+        assert!(b.contains_key(250));
+        assert!(!b.contains_key(500));
+        assert_eq!(b.iter().len(), 1000);
+
+        b.extend((500..600).map(|k| (k, k)));
+        assert_eq!(b.len(), 1100);
+        assert_eq!(b.last(), Some((599, 599)));
+        assert_eq!(b.remove(599), Some(599));
+        assert_eq!(b.remove(599), None);
+        assert_eq!(b.len(), 1099);
+        b.validate().unwrap();
+
+        let from_sorted: BTree<u32, u32, 2> = BTree::from_sorted(&b.iter().collect::<Vec<_>>());
+        assert_eq!(from_sorted.len(), 1099);
+        from_sorted.validate().unwrap();
+
+        let borrowed: Vec<_> = b.iter().collect();
+        let mut owned = b.clone().into_iter();
+        assert_eq!(owned.len(), 1099);
+        owned.next();
+        assert_eq!(owned.size_hint(), (1098, Some(1098)));
+        assert_eq!(owned.collect::<Vec<_>>(), &borrowed[1..]);
+
+        let snapshot = b.snapshot();
+        b.clear();
+        b.validate().unwrap();
+        assert!(b.is_empty());
+        assert_eq!(b.first(), None);
+        assert_eq!(b, BTree::new());
+        assert_eq!(snapshot.len(), 1099);
+        assert_eq!(snapshot.first(), Some((0, 0)));
+    }
 }
@@ -0,0 +1,352 @@
+//! A B Tree which many threads can search and insert into at once.
+//!
+//! `btree::BTree` can only be changed through `&mut self`, so sharing one
+//! between threads means putting a lock around the whole tree. Here instead
+//! each node has its own `RwLock`, and operations use latch crabbing: a thread
+//! walking down the tree locks a child before unlocking its parent, so no
+//! other thread can change the link between them in the meantime.
+//!
+//! Inserts split full nodes on the way down, as in Cormen's insert. That way
+//! an insert never has to go back up the tree, so once a child is locked (and
+//! split if it was full) its parent can be unlocked: the insert can't change
+//! the parent any more. Searches hold read locks and inserts write locks, so
+//! any number of searches can share a node, and searches and inserts in
+//! different parts of the tree don't block each other at all.
+//!
+//! Locks are always taken from the root down, so there is no deadlock. Delete
+//! is not implemented.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use key::Key;
+use model::Model;
+use node_search::{Binary, NodeSearch};
+
+type NodeRef<K, I> = Arc<RwLock<Node<K, I>>>;
+
+struct Node<K, I> {
+    keys: Vec<K>,
+    indices: Vec<I>,
+
+    // empty for a leaf
+    children: Vec<NodeRef<K, I>>,
+}
+
+impl<K, I> Node<K, I> {
+    fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+}
+
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().expect("ConcurrentBTree: lock poisoned")
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().expect("ConcurrentBTree: lock poisoned")
+}
+
+/// A B Tree with minimum degree `T` that can be searched and inserted into
+/// from many threads at once, through a shared reference.
+pub struct ConcurrentBTree<K, I, const T: usize = 8> {
+    // The root can be replaced when it splits, so it is behind a lock of its
+    // own. Operations hold this lock until they have locked the root node.
+    root: RwLock<NodeRef<K, I>>,
+    len: AtomicUsize,
+}
+
+impl<K, I, const T: usize> Default for ConcurrentBTree<K, I, T>
+where
+    K: Key,
+    I: Copy,
+{
+    fn default() -> Self {
+        assert!(
+            T >= 2,
+            "ConcurrentBTree: the minimum degree must be at least 2"
+        );
+        ConcurrentBTree {
+            root: RwLock::new(Arc::new(RwLock::new(Node {
+                keys: Vec::with_capacity(2 * T - 1),
+                indices: Vec::with_capacity(2 * T - 1),
+                children: Vec::new(),
+            }))),
+            len: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<K, I, const T: usize> ConcurrentBTree<K, I, T>
+where
+    K: Key,
+    I: Copy,
+{
+    /// Create a new, empty tree.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The number of entries in the tree.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    /// Whether the tree has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Find the index with this key that was inserted before any other index
+    /// with this key, or `None` if the key is not in the tree.
+    pub fn search(&self, key: K) -> Option<I> {
+        match self.lower_bound(key) {
+            Some((k, index)) if k.key_eq(&key) => Some(index),
+            _ => None,
+        }
+    }
+
+    /// The first entry whose key is not less than `key`.
+    pub fn lower_bound(&self, key: K) -> Option<(K, I)> {
+        self.descend(|keys| Binary::rank(keys, key))
+    }
+
+    /// The first entry whose key is greater than `key`.
+    pub fn upper_bound(&self, key: K) -> Option<(K, I)> {
+        self.descend(|keys| keys.iter().take_while(|k| k.key_le(&key)).count())
+    }
+
+    /// Insert `key` into the tree, mapping to `index`. If the key is already
+    /// present, the new entry goes after the existing ones.
+    pub fn insert(&self, key: K, index: I) {
+        let mut root_ref = write(&self.root);
+        let root = root_ref.clone();
+        let mut guard = write(&root);
+        if guard.keys.len() == 2 * T - 1 {
+            let new_root = Arc::new(RwLock::new(Node {
+                keys: Vec::with_capacity(2 * T - 1),
+                indices: Vec::with_capacity(2 * T - 1),
+                children: vec![root.clone()],
+            }));
+            let mut new_guard = write(&new_root);
+            Self::split_child(&mut new_guard, 0, &mut guard);
+            *root_ref = new_root.clone();
+            drop(guard);
+            drop(root_ref);
+            Self::insert_from(new_guard, key, index);
+        } else {
+            drop(root_ref);
+            Self::insert_from(guard, key, index);
+        }
+        self.len.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // Walk from the root to a leaf, using `rank` to choose the position in
+    // each node, and return the entry at the last such position that held
+    // one.
+    fn descend<F>(&self, rank: F) -> Option<(K, I)>
+    where
+        F: Fn(&[K]) -> usize,
+    {
+        let root_ref = read(&self.root);
+        let root = root_ref.clone();
+        let guard = read(&root);
+        drop(root_ref);
+        Self::descend_from(guard, &rank, None)
+    }
+
+    fn descend_from<F>(
+        guard: RwLockReadGuard<'_, Node<K, I>>,
+        rank: &F,
+        mut result: Option<(K, I)>,
+    ) -> Option<(K, I)>
+    where
+        F: Fn(&[K]) -> usize,
+    {
+        let i = rank(&guard.keys);
+        if i < guard.keys.len() {
+            result = Some((guard.keys[i], guard.indices[i]));
+        }
+        if guard.is_leaf() {
+            return result;
+        }
+        let child = guard.children[i].clone();
+        let child_guard = read(&child);
+        drop(guard);
+        Self::descend_from(child_guard, rank, result)
+    }
+
+    // Insert into the subtree of the locked, nonfull node `guard`.
+    fn insert_from(mut guard: RwLockWriteGuard<'_, Node<K, I>>, key: K, index: I) {
+        let mut i = guard.keys.iter().take_while(|k| k.key_le(&key)).count();
+        if guard.is_leaf() {
+            guard.keys.insert(i, key);
+            guard.indices.insert(i, index);
+            return;
+        }
+
+        let child = guard.children[i].clone();
+        let mut child_guard = write(&child);
+        if child_guard.keys.len() == 2 * T - 1 {
+            Self::split_child(&mut guard, i, &mut child_guard);
+            if guard.keys[i].key_le(&key) {
+                // The new sibling can only be reached through `guard`, so no
+                // other thread can hold its lock.
+                i += 1;
+                let sibling = guard.children[i].clone();
+                let sibling_guard = write(&sibling);
+                drop(child_guard);
+                drop(guard);
+                Self::insert_from(sibling_guard, key, index);
+                return;
+            }
+        }
+        drop(guard);
+        Self::insert_from(child_guard, key, index);
+    }
+
+    // Split the full child `y`, which is child `i` of `x`. Both must be
+    // locked.
+    fn split_child(x: &mut Node<K, I>, i: usize, y: &mut Node<K, I>) {
+        let mut z = Node {
+            keys: Vec::with_capacity(2 * T - 1),
+            indices: Vec::with_capacity(2 * T - 1),
+            children: Vec::new(),
+        };
+        z.keys.extend(y.keys.drain(T..));
+        z.indices.extend(y.indices.drain(T..));
+        if !y.is_leaf() {
+            z.children.extend(y.children.drain(T..));
+        }
+        let key = y.keys.pop().unwrap();
+        let index = y.indices.pop().unwrap();
+        x.keys.insert(i, key);
+        x.indices.insert(i, index);
+        x.children.insert(i + 1, Arc::new(RwLock::new(z)));
+    }
+}
+
+impl<K, I, const T: usize> Model<K, I> for ConcurrentBTree<K, I, T>
+where
+    K: Key,
+    I: Copy,
+{
+    fn eval(&self, key: K) -> Option<I> {
+        self.search(key)
+    }
+
+    fn eval_lower_bound(&self, key: K) -> Option<I> {
+        self.lower_bound(key).map(|(_, index)| index)
+    }
+
+    fn eval_upper_bound(&self, key: K) -> Option<I> {
+        self.upper_bound(key).map(|(_, index)| index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    use btree::BTree;
+
+    const WRITERS: u32 = 4;
+    const READERS: u32 = 4;
+    const PER_WRITER: u32 = 20000;
+
+    // The key written by writer `w` in its `j`th insert. Each writer's keys
+    // are distinct from everyone else's, and are inserted in a scrambled
+    // order so that inserts land all over the tree.
+    fn key(w: u32, j: u32) -> u32 {
+        ((j.wrapping_mul(7919) % PER_WRITER) * WRITERS + w) * 2
+    }
+
+    #[test]
+    fn stress() {
+        let tree: Arc<ConcurrentBTree<u32, u32, 4>> = Arc::new(ConcurrentBTree::new());
+        let progress: Arc<Vec<AtomicUsize>> =
+            Arc::new((0..WRITERS).map(|_| AtomicUsize::new(0)).collect());
+        let done = Arc::new(AtomicBool::new(false));
+
+        let writers: Vec<_> = (0..WRITERS)
+            .map(|w| {
+                let tree = tree.clone();
+                let progress = progress.clone();
+                thread::spawn(move || {
+                    for j in 0..PER_WRITER {
+                        tree.insert(key(w, j), key(w, j) + 1);
+                        progress[w as usize].store(j as usize + 1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+
+        let readers: Vec<_> = (0..READERS)
+            .map(|r| {
+                let tree = tree.clone();
+                let progress = progress.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    let mut rng = XorShiftRng::from_seed([r as u8 + 1; 16]);
+                    while !done.load(Ordering::SeqCst) {
+                        // anything a writer has finished inserting must be
+                        // visible, with the right index
+                        let w = rng.gen_range(0, WRITERS);
+                        let finished = progress[w as usize].load(Ordering::SeqCst) as u32;
+                        if finished > 0 {
+                            let k = key(w, rng.gen_range(0, finished));
+                            assert_eq!(tree.search(k), Some(k + 1));
+                        }
+                        // keys that are never inserted are never found, and
+                        // their lower bound is a real entry
+                        let odd = 2 * rng.gen_range(0, WRITERS * PER_WRITER) + 1;
+                        assert_eq!(tree.search(odd), None);
+                        if let Some((k, index)) = tree.lower_bound(odd) {
+                            assert!(k > odd);
+                            assert_eq!(index, k + 1);
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        // compare against the same inserts made sequentially
+        let mut reference: BTree<u32, u32> = BTree::new();
+        for w in 0..WRITERS {
+            for j in 0..PER_WRITER {
+                reference.insert(key(w, j), key(w, j) + 1);
+            }
+        }
+        assert_eq!(tree.len(), (WRITERS * PER_WRITER) as usize);
+        for k in 0..2 * WRITERS * PER_WRITER + 1 {
+            assert_eq!(tree.search(k), reference.search(k));
+            assert_eq!(tree.lower_bound(k), reference.lower_bound(k));
+            assert_eq!(tree.upper_bound(k), reference.upper_bound(k));
+        }
+    }
+
+    #[test]
+    fn duplicates() {
+        let tree: ConcurrentBTree<u32, u32, 2> = ConcurrentBTree::new();
+        for i in 0..200 {
+            tree.insert(i % 3, i);
+        }
+        assert_eq!(tree.search(0), Some(0));
+        assert_eq!(tree.search(1), Some(1));
+        assert_eq!(tree.search(2), Some(2));
+        assert_eq!(tree.search(3), None);
+    }
+}
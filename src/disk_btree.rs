@@ -0,0 +1,765 @@
+//! A B Tree stored in a file, for data that doesn't fit in memory.
+//!
+//! This is the same structure as `btree::BTree`, but each node lives in its own
+//! fixed size page of the file, and children are referred to by page number
+//! rather than by `u32` index into a `Vec`. Pages are read through a
+//! `BufferPool`, which keeps a bounded number of them in memory and evicts the
+//! least recently used one when it needs room. Changed pages are written back
+//! when they are evicted or when the tree is flushed; `flush` writes them to
+//! the operating system, and `sync` additionally waits for them to reach the
+//! disk.
+//!
+//! Page 0 of the file is a header giving the page size, key and index sizes,
+//! root page and entry count. Each other page holds one node: a leaf flag and
+//! key count, followed by arrays of keys, indices, and (for interior nodes)
+//! child page numbers, all little endian.
+//!
+//! Keys and indices must implement `Fixed`, which gives them a fixed size
+//! encoding. Delete is not implemented.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+
+use key::Key;
+use model::Model;
+
+/// The size in bytes of each page of the file.
+pub const PAGE_SIZE: usize = 4096;
+
+/// The number of a page in the file.
+pub type PageId = u32;
+
+const MAGIC: &[u8; 8] = b"LISBTREE";
+const VERSION: u32 = 1;
+
+// Layout of the header page.
+const HEADER_VERSION: usize = 8;
+const HEADER_PAGE_SIZE: usize = 12;
+const HEADER_KEY_SIZE: usize = 16;
+const HEADER_INDEX_SIZE: usize = 20;
+const HEADER_ROOT: usize = 24;
+const HEADER_PAGE_COUNT: usize = 28;
+const HEADER_LEN: usize = 32;
+
+// Each node page starts with a leaf flag byte, a padding byte, and a `u16` key
+// count.
+const NODE_HEADER: usize = 4;
+
+/// A type with a fixed size, little endian encoding, usable as a key or index
+/// of a `DiskBTree`.
+pub trait Fixed: Copy {
+    /// The size of the encoding in bytes.
+    const SIZE: usize;
+
+    /// Write `self` into the first `SIZE` bytes of `buf`.
+    fn encode(self, buf: &mut [u8]);
+
+    /// Read a value from the first `SIZE` bytes of `buf`.
+    fn decode(buf: &[u8]) -> Self;
+}
+
+macro_rules! impl_fixed {
+    ($t:ty) => {
+        impl Fixed for $t {
+            const SIZE: usize = ::std::mem::size_of::<$t>();
+
+            fn encode(self, buf: &mut [u8]) {
+                buf[..Self::SIZE].copy_from_slice(&self.to_le_bytes());
+            }
+
+            fn decode(buf: &[u8]) -> Self {
+                let mut bytes = [0u8; ::std::mem::size_of::<$t>()];
+                bytes.copy_from_slice(&buf[..Self::SIZE]);
+                <$t>::from_le_bytes(bytes)
+            }
+        }
+    };
+}
+
+impl_fixed!(f32);
+impl_fixed!(f64);
+impl_fixed!(u32);
+impl_fixed!(u64);
+impl_fixed!(i32);
+impl_fixed!(i64);
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::decode(&buf[offset..])
+}
+
+fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    value.encode(&mut buf[offset..]);
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Marks the ends of the LRU list in `BufferPool`.
+const NO_FRAME: usize = usize::MAX;
+
+struct Frame {
+    page: PageId,
+    data: Box<[u8]>,
+    dirty: bool,
+
+    // neighbours in the LRU list
+    prev: usize,
+    next: usize,
+}
+
+/// A bounded cache of the pages of a file, evicting the least recently used
+/// page when full.
+///
+/// Frames are kept in a doubly linked list ordered by last use, so finding a
+/// page, marking it used and choosing a victim are all constant time.
+pub struct BufferPool {
+    file: File,
+    frames: Vec<Frame>,
+    map: HashMap<PageId, usize>,
+    capacity: usize,
+
+    // most and least recently used frames
+    head: usize,
+    tail: usize,
+
+    page_count: u32,
+    reads: u64,
+    writes: u64,
+}
+
+impl BufferPool {
+    /// A pool holding at most `capacity` pages of `file`, which currently has
+    /// `page_count` pages.
+    pub fn new(file: File, capacity: usize, page_count: u32) -> Self {
+        assert!(capacity > 0, "BufferPool: capacity must be positive");
+        BufferPool {
+            file,
+            frames: Vec::with_capacity(capacity),
+            map: HashMap::with_capacity(capacity),
+            capacity,
+            head: NO_FRAME,
+            tail: NO_FRAME,
+            page_count,
+            reads: 0,
+            writes: 0,
+        }
+    }
+
+    /// The number of pages in the file, including ones not yet written.
+    pub fn page_count(&self) -> u32 {
+        self.page_count
+    }
+
+    /// How many pages have been read from and written to the file.
+    pub fn io_counts(&self) -> (u64, u64) {
+        (self.reads, self.writes)
+    }
+
+    /// The contents of page `page`.
+    pub fn page(&mut self, page: PageId) -> io::Result<&[u8]> {
+        let frame = self.fetch(page, true)?;
+        Ok(&self.frames[frame].data)
+    }
+
+    /// The contents of page `page`, to be modified. The page will be written
+    /// back to the file when it is evicted or flushed.
+    pub fn page_mut(&mut self, page: PageId) -> io::Result<&mut [u8]> {
+        let frame = self.fetch(page, true)?;
+        self.frames[frame].dirty = true;
+        Ok(&mut self.frames[frame].data)
+    }
+
+    /// Add a zeroed page to the end of the file, returning its number.
+    pub fn allocate(&mut self) -> io::Result<PageId> {
+        let page = self.page_count;
+        self.page_count += 1;
+        let frame = self.fetch(page, false)?;
+        self.frames[frame].dirty = true;
+        Ok(page)
+    }
+
+    /// Write every modified page to the file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        for i in 0..self.frames.len() {
+            if self.frames[i].dirty {
+                self.write_frame(i)?;
+            }
+        }
+        self.file.flush()
+    }
+
+    /// Flush, and wait for the file's contents to reach the disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.file.sync_all()
+    }
+
+    fn write_frame(&mut self, i: usize) -> io::Result<()> {
+        let frame = &mut self.frames[i];
+        self.file
+            .seek(SeekFrom::Start(frame.page as u64 * PAGE_SIZE as u64))?;
+        self.file.write_all(&frame.data)?;
+        frame.dirty = false;
+        self.writes += 1;
+        Ok(())
+    }
+
+    // Find the frame holding `page`, loading it (from the file if `read`, as
+    // zeroes otherwise) if necessary, and make it the most recently used.
+    fn fetch(&mut self, page: PageId, read: bool) -> io::Result<usize> {
+        if let Some(&i) = self.map.get(&page) {
+            self.unlink(i);
+            self.push_front(i);
+            return Ok(i);
+        }
+
+        let i = if self.frames.len() < self.capacity {
+            self.frames.push(Frame {
+                page,
+                data: vec![0; PAGE_SIZE].into_boxed_slice(),
+                dirty: false,
+                prev: NO_FRAME,
+                next: NO_FRAME,
+            });
+            self.frames.len() - 1
+        } else {
+            let victim = self.tail;
+            if self.frames[victim].dirty {
+                self.write_frame(victim)?;
+            }
+            self.unlink(victim);
+            self.map.remove(&self.frames[victim].page);
+            victim
+        };
+
+        {
+            let frame = &mut self.frames[i];
+            frame.page = page;
+            if read {
+                self.file
+                    .seek(SeekFrom::Start(page as u64 * PAGE_SIZE as u64))?;
+                self.file.read_exact(&mut frame.data)?;
+                self.reads += 1;
+            } else {
+                for byte in frame.data.iter_mut() {
+                    *byte = 0;
+                }
+            }
+        }
+        self.map.insert(page, i);
+        self.push_front(i);
+        Ok(i)
+    }
+
+    fn unlink(&mut self, i: usize) {
+        let (prev, next) = (self.frames[i].prev, self.frames[i].next);
+        if prev == NO_FRAME {
+            self.head = next;
+        } else {
+            self.frames[prev].next = next;
+        }
+        if next == NO_FRAME {
+            self.tail = prev;
+        } else {
+            self.frames[next].prev = prev;
+        }
+    }
+
+    fn push_front(&mut self, i: usize) {
+        self.frames[i].prev = NO_FRAME;
+        self.frames[i].next = self.head;
+        if self.head == NO_FRAME {
+            self.tail = i;
+        } else {
+            self.frames[self.head].prev = i;
+        }
+        self.head = i;
+    }
+}
+
+// A node read out of its page, for the operations that modify nodes.
+struct Node<K, I> {
+    leaf: bool,
+    keys: Vec<K>,
+    indices: Vec<I>,
+    children: Vec<PageId>,
+}
+
+/// A B Tree stored in a file, mapping keys to indices.
+///
+/// Reads go through a `BufferPool`, which needs to be mutated even for
+/// lookups, so it is kept in a `RefCell` and a `DiskBTree` can't be shared
+/// between threads.
+pub struct DiskBTree<K, I> {
+    pool: RefCell<BufferPool>,
+    root: PageId,
+    len: usize,
+
+    // minimum degree, chosen so that a node fills a page
+    t: usize,
+
+    phantom: PhantomData<(K, I)>,
+}
+
+impl<K, I> DiskBTree<K, I>
+where
+    K: Fixed + Key,
+    I: Fixed,
+{
+    /// Create a new, empty tree in the file at `path`, replacing any existing
+    /// file, and caching at most `pool_pages` pages in memory.
+    pub fn create<P>(path: P, pool_pages: usize) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let mut pool = BufferPool::new(file, pool_pages, 0);
+        pool.allocate()?;
+        let root = pool.allocate()?;
+        pool.page_mut(root)?[0] = 1;
+        {
+            let header = pool.page_mut(0)?;
+            header[..8].copy_from_slice(MAGIC);
+            write_u32(header, HEADER_VERSION, VERSION);
+            write_u32(header, HEADER_PAGE_SIZE, PAGE_SIZE as u32);
+            write_u32(header, HEADER_KEY_SIZE, K::SIZE as u32);
+            write_u32(header, HEADER_INDEX_SIZE, I::SIZE as u32);
+        }
+        let mut tree = DiskBTree {
+            pool: RefCell::new(pool),
+            root,
+            len: 0,
+            t: Self::min_degree(),
+            phantom: PhantomData,
+        };
+        tree.flush()?;
+        Ok(tree)
+    }
+
+    /// Open a tree previously created with `create`, caching at most
+    /// `pool_pages` pages in memory.
+    pub fn open<P>(path: P, pool_pages: usize) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut header = vec![0u8; PAGE_SIZE];
+        file.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(invalid_data("not a DiskBTree file"));
+        }
+        if read_u32(&header, HEADER_VERSION) != VERSION {
+            return Err(invalid_data("unsupported DiskBTree version"));
+        }
+        if read_u32(&header, HEADER_PAGE_SIZE) as usize != PAGE_SIZE {
+            return Err(invalid_data("DiskBTree file has a different page size"));
+        }
+        if read_u32(&header, HEADER_KEY_SIZE) as usize != K::SIZE
+            || read_u32(&header, HEADER_INDEX_SIZE) as usize != I::SIZE
+        {
+            return Err(invalid_data(
+                "DiskBTree file has different key or index sizes",
+            ));
+        }
+        let root = read_u32(&header, HEADER_ROOT);
+        let page_count = read_u32(&header, HEADER_PAGE_COUNT);
+        let len = u64::decode(&header[HEADER_LEN..]);
+        if root == 0 || root >= page_count {
+            return Err(invalid_data("DiskBTree file has an invalid root"));
+        }
+        let len = usize::try_from(len)
+            .map_err(|_| invalid_data("DiskBTree file has too many entries"))?;
+        Ok(DiskBTree {
+            pool: RefCell::new(BufferPool::new(file, pool_pages, page_count)),
+            root,
+            len,
+            t: Self::min_degree(),
+            phantom: PhantomData,
+        })
+    }
+
+    /// The number of entries in the tree.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the tree has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The minimum degree of the tree, which is as large as a page allows.
+    pub fn min_degree() -> usize {
+        (PAGE_SIZE - NODE_HEADER + K::SIZE + I::SIZE) / (2 * (K::SIZE + I::SIZE + 4))
+    }
+
+    /// How many pages have been read from and written to the file since it
+    /// was opened.
+    pub fn io_counts(&self) -> (u64, u64) {
+        self.pool.borrow().io_counts()
+    }
+
+    /// Find the index with this key that was inserted before any other index
+    /// with this key, or `None` if the key is not in the tree.
+    pub fn search(&self, key: K) -> io::Result<Option<I>> {
+        Ok(match self.lower_bound(key)? {
+            Some((k, index)) if k.key_eq(&key) => Some(index),
+            _ => None,
+        })
+    }
+
+    /// The first entry whose key is not less than `key`.
+    pub fn lower_bound(&self, key: K) -> io::Result<Option<(K, I)>> {
+        self.descend(|keys| keys.rank(key, false))
+    }
+
+    /// The first entry whose key is greater than `key`.
+    pub fn upper_bound(&self, key: K) -> io::Result<Option<(K, I)>> {
+        self.descend(|keys| keys.rank(key, true))
+    }
+
+    /// Insert `key` into the tree, mapping to `index`. If the key is already
+    /// present, the new entry goes after the existing ones.
+    pub fn insert(&mut self, key: K, index: I) -> io::Result<()> {
+        let mut root = self.read_node(self.root)?;
+        if root.keys.len() == 2 * self.t - 1 {
+            let s = self.pool.get_mut().allocate()?;
+            let mut new_root = Node {
+                leaf: false,
+                keys: Vec::new(),
+                indices: Vec::new(),
+                children: vec![self.root],
+            };
+            self.split_child(&mut new_root, 0)?;
+            self.write_node(s, &new_root)?;
+            self.root = s;
+            root = new_root;
+        }
+
+        let mut x = self.root;
+        let mut node = root;
+        while !node.leaf {
+            let mut i = node.keys.iter().take_while(|k| k.key_le(&key)).count();
+            let child = self.read_node(node.children[i])?;
+            if child.keys.len() == 2 * self.t - 1 {
+                self.split_child(&mut node, i)?;
+                self.write_node(x, &node)?;
+                if node.keys[i].key_le(&key) {
+                    i += 1;
+                }
+                x = node.children[i];
+                node = self.read_node(x)?;
+            } else {
+                x = node.children[i];
+                node = child;
+            }
+        }
+        let i = node.keys.iter().take_while(|k| k.key_le(&key)).count();
+        node.keys.insert(i, key);
+        node.indices.insert(i, index);
+        self.write_node(x, &node)?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Write the header and all modified pages to the file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        write_header_and_flush(self)
+    }
+
+    /// Flush, and wait for the file's contents to reach the disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.pool.get_mut().sync()
+    }
+
+    // Walk from the root to a leaf, using `rank` to choose the position in
+    // each node, and return the entry at the last such position that held
+    // one.
+    fn descend<F>(&self, rank: F) -> io::Result<Option<(K, I)>>
+    where
+        F: Fn(&PageKeys<K>) -> usize,
+    {
+        let mut pool = self.pool.borrow_mut();
+        let capacity = 2 * self.t - 1;
+        let mut result = None;
+        let mut x = self.root;
+        loop {
+            let page = pool.page(x)?;
+            let count = u16::from_le_bytes([page[2], page[3]]) as usize;
+            let keys = PageKeys {
+                page,
+                count,
+                phantom: PhantomData,
+            };
+            let i = rank(&keys);
+            if i < count {
+                let index_offset = NODE_HEADER + capacity * K::SIZE + i * I::SIZE;
+                result = Some((keys.get(i), I::decode(&page[index_offset..])));
+            }
+            if page[0] != 0 {
+                return Ok(result);
+            }
+            let child_offset = NODE_HEADER + capacity * (K::SIZE + I::SIZE) + 4 * i;
+            x = read_u32(page, child_offset);
+        }
+    }
+
+    fn read_node(&self, x: PageId) -> io::Result<Node<K, I>> {
+        let capacity = 2 * self.t - 1;
+        let mut pool = self.pool.borrow_mut();
+        let page = pool.page(x)?;
+        let leaf = page[0] != 0;
+        let count = u16::from_le_bytes([page[2], page[3]]) as usize;
+        let keys_at = NODE_HEADER;
+        let indices_at = keys_at + capacity * K::SIZE;
+        let children_at = indices_at + capacity * I::SIZE;
+        Ok(Node {
+            leaf,
+            keys: (0..count)
+                .map(|i| K::decode(&page[keys_at + i * K::SIZE..]))
+                .collect(),
+            indices: (0..count)
+                .map(|i| I::decode(&page[indices_at + i * I::SIZE..]))
+                .collect(),
+            children: if leaf {
+                Vec::new()
+            } else {
+                (0..count + 1)
+                    .map(|i| read_u32(page, children_at + 4 * i))
+                    .collect()
+            },
+        })
+    }
+
+    fn write_node(&mut self, x: PageId, node: &Node<K, I>) -> io::Result<()> {
+        let capacity = 2 * self.t - 1;
+        let page = self.pool.get_mut().page_mut(x)?;
+        page[0] = node.leaf as u8;
+        page[2..4].copy_from_slice(&(node.keys.len() as u16).to_le_bytes());
+        let keys_at = NODE_HEADER;
+        let indices_at = keys_at + capacity * K::SIZE;
+        let children_at = indices_at + capacity * I::SIZE;
+        for (i, &key) in node.keys.iter().enumerate() {
+            key.encode(&mut page[keys_at + i * K::SIZE..]);
+        }
+        for (i, &index) in node.indices.iter().enumerate() {
+            index.encode(&mut page[indices_at + i * I::SIZE..]);
+        }
+        for (i, &child) in node.children.iter().enumerate() {
+            write_u32(page, children_at + 4 * i, child);
+        }
+        Ok(())
+    }
+
+    // Split the full child `i` of `x`, as in `BTree::split_child`. The caller
+    // is responsible for writing `x` back.
+    fn split_child(&mut self, x: &mut Node<K, I>, i: usize) -> io::Result<()> {
+        let t = self.t;
+        let y_page = x.children[i];
+        let mut y = self.read_node(y_page)?;
+        let z = Node {
+            leaf: y.leaf,
+            keys: y.keys.split_off(t),
+            indices: y.indices.split_off(t),
+            children: if y.leaf {
+                Vec::new()
+            } else {
+                y.children.split_off(t)
+            },
+        };
+        let key = y.keys.pop().unwrap();
+        let index = y.indices.pop().unwrap();
+        let z_page = self.pool.get_mut().allocate()?;
+        self.write_node(y_page, &y)?;
+        self.write_node(z_page, &z)?;
+        x.keys.insert(i, key);
+        x.indices.insert(i, index);
+        x.children.insert(i + 1, z_page);
+        Ok(())
+    }
+}
+
+// The keys of a node, read in place from its page.
+struct PageKeys<'a, K> {
+    page: &'a [u8],
+    count: usize,
+    phantom: PhantomData<K>,
+}
+
+impl<'a, K> PageKeys<'a, K>
+where
+    K: Fixed + Key,
+{
+    fn get(&self, i: usize) -> K {
+        K::decode(&self.page[NODE_HEADER + i * K::SIZE..])
+    }
+
+    // The number of keys less than `key`, or at most `key` if `after`, by
+    // binary search.
+    fn rank(&self, key: K, after: bool) -> usize {
+        let (mut lo, mut hi) = (0, self.count);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let k = self.get(mid);
+            let before = if after {
+                k.key_le(&key)
+            } else {
+                k.key_lt(&key)
+            };
+            if before {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+}
+
+impl<K, I> Drop for DiskBTree<K, I> {
+    fn drop(&mut self) {
+        // Errors can't be reported from here; call `flush` or `sync` first to
+        // see them.
+        let _ = write_header_and_flush(self);
+    }
+}
+
+// Update the parts of the header that change, and flush the pool. This is a
+// free function without bounds on `K` and `I` so that `Drop` can use it.
+fn write_header_and_flush<K, I>(tree: &mut DiskBTree<K, I>) -> io::Result<()> {
+    let (root, len) = (tree.root, tree.len);
+    let pool = tree.pool.get_mut();
+    let page_count = pool.page_count();
+    let header = pool.page_mut(0)?;
+    write_u32(header, HEADER_ROOT, root);
+    write_u32(header, HEADER_PAGE_COUNT, page_count);
+    (len as u64).encode(&mut header[HEADER_LEN..]);
+    pool.flush()
+}
+
+/// `Model` can't report errors, so these methods panic if a page can't be
+/// read. Use `search`, `lower_bound` and `upper_bound` to handle I/O errors.
+impl<K, I> Model<K, I> for DiskBTree<K, I>
+where
+    K: Fixed + Key,
+    I: Fixed,
+{
+    fn eval(&self, key: K) -> Option<I> {
+        self.search(key).expect("DiskBTree: unable to read page")
+    }
+
+    fn eval_lower_bound(&self, key: K) -> Option<I> {
+        self.lower_bound(key)
+            .expect("DiskBTree: unable to read page")
+            .map(|(_, index)| index)
+    }
+
+    fn eval_upper_bound(&self, key: K) -> Option<I> {
+        self.upper_bound(key)
+            .expect("DiskBTree: unable to read page")
+            .map(|(_, index)| index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{Rng, SeedableRng, XorShiftRng};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn insert_search_reopen() {
+        let file = NamedTempFile::new().unwrap();
+        let mut rng = XorShiftRng::from_seed([1; 16]);
+        let mut reference: Vec<(u32, u32)> = Vec::new();
+        {
+            // a tiny pool, so that pages are evicted all the time
+            let mut tree: DiskBTree<u32, u32> = DiskBTree::create(file.path(), 3).unwrap();
+            for i in 0..20000 {
+                let key = rng.gen_range(0, 5000);
+                tree.insert(key, i).unwrap();
+                reference.push((key, i));
+            }
+            reference.sort_by_key(|p| p.0);
+            for key in 0..5001 {
+                let lower = reference.iter().find(|p| p.0 >= key).cloned();
+                assert_eq!(tree.lower_bound(key).unwrap(), lower);
+                assert_eq!(tree.eval(key), lower.filter(|p| p.0 == key).map(|p| p.1));
+            }
+            tree.sync().unwrap();
+        }
+
+        let tree: DiskBTree<u32, u32> = DiskBTree::open(file.path(), 16).unwrap();
+        assert_eq!(tree.len(), 20000);
+        for key in 0..5001 {
+            let lower = reference.iter().find(|p| p.0 >= key).cloned();
+            let upper = reference.iter().find(|p| p.0 > key).cloned();
+            assert_eq!(tree.lower_bound(key).unwrap(), lower);
+            assert_eq!(tree.upper_bound(key).unwrap(), upper);
+        }
+    }
+
+    #[test]
+    fn drop_flushes() {
+        let file = NamedTempFile::new().unwrap();
+        {
+            let mut tree: DiskBTree<f32, u64> = DiskBTree::create(file.path(), 2).unwrap();
+            for i in 0..3000 {
+                tree.insert(i as f32, i).unwrap();
+            }
+        }
+        let tree: DiskBTree<f32, u64> = DiskBTree::open(file.path(), 2).unwrap();
+        assert_eq!(tree.len(), 3000);
+        for i in 0..3000 {
+            assert_eq!(tree.search(i as f32).unwrap(), Some(i));
+        }
+        assert_eq!(tree.search(0.5).unwrap(), None);
+    }
+
+    #[test]
+    fn open_rejects_mismatches() {
+        let file = NamedTempFile::new().unwrap();
+        drop(DiskBTree::<u32, u32>::create(file.path(), 4).unwrap());
+        assert!(DiskBTree::<u64, u32>::open(file.path(), 4).is_err());
+        assert!(DiskBTree::<u32, u32>::open(file.path(), 4).is_ok());
+
+        let other = NamedTempFile::new().unwrap();
+        other.as_file().set_len(PAGE_SIZE as u64).unwrap();
+        let error = DiskBTree::<u32, u32>::open(other.path(), 4).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn buffer_pool_lru() {
+        let file = NamedTempFile::new().unwrap();
+        let mut pool = BufferPool::new(file.reopen().unwrap(), 2, 0);
+        for i in 0..4u8 {
+            let page = pool.allocate().unwrap();
+            pool.page_mut(page).unwrap()[0] = i + 1;
+        }
+        // pages 2 and 3 are cached; 0 and 1 were written on eviction
+        assert_eq!(pool.io_counts(), (0, 2));
+        assert_eq!(pool.page(3).unwrap()[0], 4);
+        assert_eq!(pool.page(0).unwrap()[0], 1);
+        assert_eq!(pool.io_counts(), (1, 3));
+        // 3 was used more recently than 2, so it is still cached
+        assert_eq!(pool.page(3).unwrap()[0], 4);
+        assert_eq!(pool.io_counts(), (1, 3));
+        pool.flush().unwrap();
+        assert_eq!(pool.page(1).unwrap()[0], 2);
+        assert_eq!(pool.page(2).unwrap()[0], 3);
+    }
+}
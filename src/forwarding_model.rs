@@ -1,58 +1,152 @@
 //! A model consisting of a top level neural net that selects one of several B
 //! Trees
+//!
+//! The model is generic over the key type `K` and index type `I`. The network
+//! sees each key through `Key::to_f64`, but the B Trees compare the keys
+//! themselves, so lookups are exact even for keys, like large `u64`s, that
+//! `f32` or `f64` can't represent.
 
 use std::fs::File;
-use std::io::{BufRead, BufReader, Read};
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::path::Path;
 use std::str::FromStr;
 
 use toml::{self, Value};
 
-use btree::BTree;
+use frozen_btree::FrozenBTree;
+use key::{Index, Key};
 use model::Model;
-use neural::Network;
+use neural::{Network, Scratch};
 
 use self::Value::*;
 
-pub struct ForwardingModel {
+// `eval_many` runs the network on this many keys at a time with
+// `Network::apply_batch`, if it has at least this many keys.
+const BATCH: usize = 1024;
+const MIN_BATCH: usize = 16;
+
+pub struct ForwardingModel<K = f32, I = u32> {
     net: Network,
-    btrees: Box<[BTree<f32, u32>]>,
-    max_prediction: u32,
+    btrees: Box<[FrozenBTree<K, I>]>,
+    max_prediction: I,
 }
 
-impl Model<f32, u32> for ForwardingModel {
-    fn eval(&self, key: f32) -> Option<u32> {
+impl<K, I> ForwardingModel<K, I>
+where
+    K: Key,
+    I: Index,
+{
+    // Which B Tree the network sends `key` to.
+    fn select(&self, key: K, buf1: &mut [f32], buf2: &mut [f32]) -> usize {
+        self.tree_for(self.net.apply_buffer(key.to_f64(), buf1, buf2))
+    }
+
+    // Which B Tree a key is in, given the network's prediction for it.
+    fn tree_for(&self, predicted_label: f32) -> usize {
+        let max_prediction = self.max_prediction.to_usize() as f32;
+        let model = ((predicted_label / max_prediction) * self.btrees.len() as f32) as usize;
+        model.min(self.btrees.len() - 1)
+    }
+
+    // The first result of `bound` over the B Trees in order, starting from
+    // the one the network sends `key` to. This assumes, as `train.py`
+    // arranges, that each B Tree holds smaller keys than the ones after it.
+    //
+    // A key that isn't present may be sent to the B Tree holding its
+    // predecessor, in which case the bound is in a later B Tree, or, since
+    // the network needn't be monotonic, to a B Tree after the one holding
+    // the bound, in which case the earlier B Trees still give results and we
+    // step back over them.
+    fn bound<F>(&self, key: K, bound: F) -> Option<I>
+    where
+        F: Fn(&FrozenBTree<K, I>) -> Option<I>,
+    {
+        let model = self.select_alloc(key);
+        let mut result = None;
+        for btree in self.btrees[..model].iter().rev() {
+            match bound(btree) {
+                Some(index) => result = Some(index),
+                None if btree.is_empty() => {}
+                None => break,
+            }
+        }
+        result.or_else(|| self.btrees[model..].iter().filter_map(bound).next())
+    }
+
+    // The data was read through `Key::canonicalize`, so queries have to be
+    // too, or a query for `-0.0` would miss a stored `0.0`. NaN is left as it
+    // is; it isn't stored, so it's never found.
+    fn query(key: K) -> K {
+        key.canonicalize().unwrap_or(key)
+    }
+
+    fn select_alloc(&self, key: K) -> usize {
         let buf_size = self.net.buf_size();
         let mut buf1 = vec![0.0f32; buf_size];
         let mut buf2 = vec![0.0f32; buf_size];
-        let predicted_label = self.net.apply_buffer(key, &mut buf1, &mut buf2);
-        let model =
-            ((predicted_label / self.max_prediction as f32) * self.btrees.len() as f32) as usize;
+        self.select(key, &mut buf1, &mut buf2)
+    }
+}
+
+impl<K, I> Model<K, I> for ForwardingModel<K, I>
+where
+    K: Key,
+    I: Index,
+{
+    fn eval(&self, key: K) -> Option<I> {
+        let key = Self::query(key);
+        let model = self.select_alloc(key);
         self.btrees[model].eval(key)
     }
 
-    fn eval_many(&self, keys: &[f32], indices: &mut [Option<u32>]) {
-        let buf_size = self.net.buf_size();
-        let mut buf1 = vec![0.0f32; buf_size];
-        let mut buf2 = vec![0.0f32; buf_size];
-        for (i, &key) in keys.iter().enumerate() {
-            let predicted_label = self.net.apply_buffer(key, &mut buf1, &mut buf2);
-            let model = ((predicted_label / self.max_prediction as f32) * self.btrees.len() as f32)
-                as usize;
-            indices[i] = self.btrees[model].eval(key)
+    fn eval_lower_bound(&self, key: K) -> Option<I> {
+        let key = Self::query(key);
+        self.bound(key, |btree| btree.eval_lower_bound(key))
+    }
+
+    fn eval_upper_bound(&self, key: K) -> Option<I> {
+        let key = Self::query(key);
+        self.bound(key, |btree| btree.eval_upper_bound(key))
+    }
+
+    fn eval_many(&self, keys: &[K], indices: &mut [Option<I>]) {
+        if keys.len() < MIN_BATCH {
+            let buf_size = self.net.buf_size();
+            let mut buf1 = vec![0.0f32; buf_size];
+            let mut buf2 = vec![0.0f32; buf_size];
+            for (i, &key) in keys.iter().enumerate() {
+                let key = Self::query(key);
+                let model = self.select(key, &mut buf1, &mut buf2);
+                indices[i] = self.btrees[model].eval(key)
+            }
+            return;
+        }
+
+        let mut scratch = Scratch::new();
+        let mut inputs = Vec::with_capacity(BATCH);
+        let mut predictions = vec![0.0f32; BATCH];
+        for (keys, indices) in keys.chunks(BATCH).zip(indices.chunks_mut(BATCH)) {
+            inputs.clear();
+            inputs.extend(keys.iter().map(|&key| key.to_f64()));
+            let predictions = &mut predictions[..keys.len()];
+            self.net.apply_batch(&inputs, predictions, &mut scratch);
+            for (i, &key) in keys.iter().enumerate() {
+                let model = self.tree_for(predictions[i]);
+                indices[i] = self.btrees[model].eval(Self::query(key))
+            }
         }
     }
 }
 
-fn value_array_arrays(v: &Value) -> Box<[Box<[u32]>]> {
+fn value_array_arrays<I: Index>(v: &Value) -> Box<[Box<[I]>]> {
     if let Array(a) = v {
-        let mut arrays: Vec<Box<[u32]>> = Vec::new();
+        let mut arrays: Vec<Box<[I]>> = Vec::new();
         for value in a.iter() {
             if let Array(immediate_array) = value {
-                let mut array: Vec<u32> = Vec::new();
+                let mut array: Vec<I> = Vec::new();
                 for integer in immediate_array.iter() {
                     if let Integer(i) = integer {
-                        array.push(*i as u32);
+                        array.push(I::from_usize(*i as usize));
                     } else {
                         panic!("Invalid TOML format");
                     }
@@ -62,52 +156,72 @@ fn value_array_arrays(v: &Value) -> Box<[Box<[u32]>]> {
                 panic!("Invalid TOML format");
             }
         }
-        return arrays.into_boxed_slice();
+        arrays.into_boxed_slice()
     } else {
         panic!("Invalid TOML format");
     }
 }
 
-pub fn read_data<P>(data_path: &P) -> Box<[f32]>
+/// Read keys from a data file, one number per line. Blank lines are skipped.
+///
+/// Each key is passed through `Key::canonicalize`, so `-0.0` is read as
+/// `0.0`. Panics, giving the line number, if a line isn't a number or is NaN.
+pub fn read_data<K, P>(data_path: &P) -> Box<[K]>
 where
+    K: Key + FromStr,
     P: AsRef<Path>,
 {
     read_data0(data_path.as_ref())
 }
 
-fn read_data0(data_path: &Path) -> Box<[f32]> {
-    use std::string::String;
+fn read_data0<K>(data_path: &Path) -> Box<[K]>
+where
+    K: Key + FromStr,
+{
     let mut result = Vec::new();
-    let mut buf = String::new();
-    let mut file = BufReader::new(File::open(data_path).expect("Unable to open data file"));
-
-    loop {
-        if let Ok(_) = file.read_line(&mut buf) {
-            if buf.len() == 0 {
-                break;
-            } else if buf.len() == 1 {
-                continue;
-            }
-            buf.pop(); // drop the newline
-            let value = f32::from_str(&buf).expect("Invalid data format");
-            result.push(value);
-            buf.clear();
-        } else {
-            panic!("file read error");
+    let file = BufReader::new(File::open(data_path).expect("Unable to open data file"));
+
+    for (i, line) in file.lines().enumerate() {
+        let line = line.expect("file read error");
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
         }
+        let value = K::from_str(line).unwrap_or_else(|_| {
+            panic!(
+                "Invalid data format in {}, line {}: {:?}",
+                data_path.display(),
+                i + 1,
+                line
+            )
+        });
+        let value = value.canonicalize().unwrap_or_else(|| {
+            panic!(
+                "Invalid data in {}, line {}: NaN can't be used as a key",
+                data_path.display(),
+                i + 1
+            )
+        });
+        result.push(value);
     }
     result.into_boxed_slice()
 }
 
-impl ForwardingModel {
-    pub fn read_toml<P>(toml_path: &P, data: &Box<[f32]>) -> Self
+impl<K, I> ForwardingModel<K, I>
+where
+    K: Key,
+    I: Index,
+{
+    /// Read a model saved by `train.py`. `data` is the sorted data it was
+    /// trained on.
+    pub fn read_toml<P>(toml_path: &P, data: &[K]) -> Self
     where
         P: AsRef<Path>,
     {
         Self::read_toml0(toml_path.as_ref(), data)
     }
 
-    fn read_toml0(toml_path: &Path, data: &Box<[f32]>) -> Self {
+    fn read_toml0(toml_path: &Path, data: &[K]) -> Self {
         use std::cmp::max;
 
         let s = {
@@ -121,7 +235,7 @@ impl ForwardingModel {
 
         let value: Value = toml::from_str(&s).expect("Unable to parse TOML file");
 
-        let table = if let &Table(ref table) = &value {
+        let table = if let Table(table) = &value {
             table
         } else {
             panic!("Bad TOML format");
@@ -133,29 +247,281 @@ impl ForwardingModel {
             panic!("Invalid TOML format");
         };
 
-        let arrays = value_array_arrays(&indices);
+        let arrays: Box<[Box<[I]>]> = value_array_arrays(indices);
 
-        let mut max_prediction: u32 = 0;
+        let mut max_prediction = I::default();
 
-        let btrees: Vec<BTree<f32, u32>> = arrays
+        // `train.py` lists the indices of each B Tree in increasing order, so
+        // since the data is sorted, so are the keys
+        let btrees: Vec<FrozenBTree<K, I>> = arrays
             .iter()
             .map(|array| {
-                let mut btree = BTree::new();
+                let keys: Vec<K> = array.iter().map(|&index| data[index.to_usize()]).collect();
                 for &index in array.iter() {
                     max_prediction = max(index, max_prediction);
-                    btree.insert(data[index as usize], index);
                 }
-                btree
+                FrozenBTree::from_sorted_parts(&keys, array)
             })
             .collect();
 
         let network = Network::from_toml(&value);
 
-        return ForwardingModel {
+        ForwardingModel {
             net: network,
             btrees: btrees.into_boxed_slice(),
             max_prediction,
+        }
+
+    }
+
+    /// The model in the format `read_toml` reads, which is the one `train.py`
+    /// saves.
+    pub fn to_toml(&self) -> Value {
+        let mut value = self.net.to_toml();
+        let indices = self
+            .btrees
+            .iter()
+            .map(|btree| {
+                let indices = btree.iter().map(|(_, index)| Integer(index.to_usize() as i64));
+                Array(indices.collect())
+            })
+            .collect();
+        if let Table(table) = &mut value {
+            table.insert("btree_indices".to_string(), Array(indices));
+        }
+        value
+    }
+
+    /// Save the model to a file, which `read_toml`, given the same data, reads
+    /// back as the same model.
+    pub fn save<P>(&self, toml_path: &P) -> io::Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let s = toml::to_string(&self.to_toml()).expect("Unable to write TOML");
+        File::create(toml_path)?.write_all(s.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ops::Range;
+    use std::panic;
+
+    use tempfile::NamedTempFile;
+
+    fn data_file(contents: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file
+    }
+
+    // A model file with the layers in `network` and a B Tree for each range
+    // of indices in `btrees`.
+    fn model_file(network: &str, btrees: &[Range<usize>]) -> NamedTempFile {
+        let lists: Vec<_> = btrees
+            .iter()
+            .map(|range| {
+                let v: Vec<_> = range.clone().map(|i| i.to_string()).collect();
+                format!("[{}]", v.join(", "))
+            })
+            .collect();
+        data_file(&format!("{}btree_indices = [{}]\n", network, lists.join(", ")))
+    }
+
+    // The layers of a network that predicts `scale * key + shift`, for keys
+    // that make that non-negative.
+    fn linear(scale: f64, shift: f64) -> ::std::string::String {
+        format!(
+            "layer0 = [[{:?}, 0.0], [{:?}, 0.0]]\n\
+             layer1 = [[1.0, 0.0, 0.0, 0.0], [0.0, 0.0]]\n\
+             layer2 = [[1.0, 0.0], [0.0]]\n",
+            scale, shift
+        )
+    }
+
+    #[test]
+    fn read_data_canonicalizes() {
+        let file = data_file("-1.5\n-0\n\n0\n2.25\n");
+        let data: Box<[f32]> = read_data(&file.path());
+        assert_eq!(&*data, &[-1.5, 0.0, 0.0, 2.25]);
+        assert!(data.iter().all(|x| x.to_bits() != (-0.0f32).to_bits()));
+    }
+
+    #[test]
+    fn read_data_rejects_nan() {
+        let file = data_file("1\n2\nNaN\n");
+        let path = file.path().to_owned();
+        let result = panic::catch_unwind(move || read_data::<f32, _>(&path));
+        let message = result.unwrap_err();
+        let message = message.downcast_ref::<::std::string::String>().unwrap();
+        assert!(message.contains("line 3"), "{}", message);
+        assert!(message.contains("NaN"), "{}", message);
+    }
+
+    #[test]
+    fn negative_zero() {
+        let data: Vec<f32> = (0..200).map(|i| i as f32 - 100.0).collect();
+        let file = model_file(&linear(0.0, 0.0), &[0..150, 150..200]);
+        let model: ForwardingModel = ForwardingModel::read_toml(&file.path(), &data);
+        assert_eq!(model.eval(-0.0), Some(100));
+        assert_eq!(model.eval_lower_bound(-0.0), Some(100));
+        assert_eq!(model.eval_upper_bound(-0.0), Some(101));
+        let keys = [-0.0; 20];
+        for &n in [1, 20].iter() {
+            let mut indices = vec![None; n];
+            model.eval_many(&keys[..n], &mut indices);
+            assert!(indices.iter().all(|&index| index == Some(100)));
+        }
+        assert_eq!(model.eval(f32::NAN), None);
+    }
+
+    #[test]
+    fn u64_keys() {
+        // keys this large and this close together are all the same as floats,
+        // but the B Trees still tell them apart
+        let data: Vec<u64> = (0..300).map(|i| (1 << 60) + 3 * i).collect();
+        assert_eq!(data[0] as f32, data[299] as f32);
+        let file = model_file(&linear(0.0, 0.0), &[0..150, 150..300]);
+        let model: ForwardingModel<u64, u64> = ForwardingModel::read_toml(&file.path(), &data);
+
+        // the network sends every key to the first B Tree
+        for (i, &key) in data[..150].iter().enumerate() {
+            assert_eq!(model.eval(key), Some(i as u64));
+            assert_eq!(model.eval(key + 1), None);
+        }
+        for (i, &key) in data.iter().enumerate() {
+            assert_eq!(model.eval_lower_bound(key), Some(i as u64));
+            assert_eq!(model.eval_lower_bound(key - 1), Some(i as u64));
+            assert_eq!(model.eval_upper_bound(key), Some(i as u64 + 1).filter(|&j| j < 300));
+        }
+        let mut indices = vec![None; 3];
+        model.eval_many(&[data[0], data[0] + 1, data[149]], &mut indices);
+        assert_eq!(indices, [Some(0), None, Some(149)]);
+
+        // enough keys to go through `Network::apply_batch`
+        let keys: Vec<u64> = data.iter().map(|&key| key + (key / 3) % 2).collect();
+        let mut indices = vec![None; keys.len()];
+        model.eval_many(&keys, &mut indices);
+        let expected: Vec<_> = keys.iter().map(|&key| model.eval(key)).collect();
+        assert_eq!(indices, expected);
+    }
+
+    #[test]
+    fn bounds_before_tree() {
+        // the even numbers below 400, with the first 100 in the first B Tree
+        let data: Vec<f32> = (0..200).map(|i| 2.0 * i as f32).collect();
+        // the network predicts `key / 2 + 1`, which is one too many, so keys
+        // from 197 up go to the second B Tree
+        let file = model_file(&linear(0.5, 1.0), &[0..100, 100..200]);
+        let model: ForwardingModel = ForwardingModel::read_toml(&file.path(), &data);
+        assert_eq!(model.select_alloc(197.0), 1);
+
+        assert_eq!(model.eval_lower_bound(197.0), Some(99));
+        assert_eq!(model.eval_upper_bound(197.0), Some(99));
+        assert_eq!(model.eval_lower_bound(199.0), Some(100));
+        assert_eq!(model.eval_upper_bound(198.0), Some(100));
+        assert_eq!(model.eval_upper_bound(398.0), None);
+        for (i, &key) in data.iter().enumerate() {
+            assert_eq!(model.eval_lower_bound(key - 1.0), Some(i as u32));
+            assert_eq!(model.eval_upper_bound(key - 1.0), Some(i as u32));
+        }
+    }
+
+    #[test]
+    fn threads() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let data: Vec<f32> = (0..200).map(|i| i as f32).collect();
+        // the network predicts `key`, so keys below 100 go to the first B Tree
+        let file = model_file(&linear(1.0, 0.0), &[0..100, 100..200]);
+        let model: ForwardingModel = ForwardingModel::read_toml(&file.path(), &data);
+        let model = Arc::new(model);
+
+        let keys: Vec<f32> = (0..400).map(|i| i as f32 / 2.0).collect();
+        let expected: Vec<_> = keys.iter().map(|&key| model.eval(key)).collect();
+        assert_eq!(expected[20], Some(10));
+        assert_eq!(expected[21], None);
+        assert_eq!(expected[398], Some(199));
+
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let model = model.clone();
+                let keys = keys.clone();
+                thread::spawn(move || {
+                    let mut many = vec![None; keys.len()];
+                    model.eval_many(&keys, &mut many);
+                    let one: Vec<_> = keys.iter().skip(t).map(|&key| model.eval(key)).collect();
+                    (many, one)
+                })
+            })
+            .collect();
+        for (t, handle) in handles.into_iter().enumerate() {
+            let (many, one) = handle.join().unwrap();
+            assert_eq!(many, expected);
+            assert_eq!(one, &expected[t..]);
+        }
+    }
+
+    #[test]
+    fn save() {
+        let data: Vec<f32> = (0..200).map(|i| (i * i) as f32 / 10.0).collect();
+        let network = "layer0 = [[0.1, -0.25, 0.3], [0.5, 1.0, -0.7]]\n\
+                       layer1 = [[1.0, 0.5, -0.5, 0.2, 0.0, 3.0], [0.1, 0.0]]\n\
+                       layer2 = [[200.0, 100.0], [100.0]]\n\
+                       activation1 = \"tanh\"\n";
+        let file = model_file(network, &[0..40, 40..120, 120..200]);
+        let model: ForwardingModel = ForwardingModel::read_toml(&file.path(), &data);
+
+        let saved = NamedTempFile::new().unwrap();
+        model.save(&saved.path()).unwrap();
+        let loaded: ForwardingModel = ForwardingModel::read_toml(&saved.path(), &data);
+        assert_eq!(loaded.net, model.net);
+        assert_eq!(loaded.btrees, model.btrees);
+        assert_eq!(loaded.max_prediction, model.max_prediction);
+
+        let mut keys: Vec<f32> = (0..4000).map(|i| i as f32).collect();
+        keys.extend_from_slice(&data);
+        let mut expected = vec![None; keys.len()];
+        let mut indices = vec![None; keys.len()];
+        model.eval_many(&keys, &mut expected);
+        loaded.eval_many(&keys, &mut indices);
+        assert_eq!(indices, expected);
+        let trees: Vec<_> = data.iter().map(|&key| model.select_alloc(key)).collect();
+        assert!((0..3).all(|i| trees.contains(&i)));
+        for &key in keys.iter() {
+            assert_eq!(loaded.eval_lower_bound(key), model.eval_lower_bound(key));
+        }
+
+        // saving the loaded model writes the same file again
+        let resaved = NamedTempFile::new().unwrap();
+        loaded.save(&resaved.path()).unwrap();
+        let read = |file: &NamedTempFile| {
+            let mut s = ::std::string::String::new();
+            File::open(file.path()).unwrap().read_to_string(&mut s).unwrap();
+            s
         };
+        assert_eq!(read(&resaved), read(&saved));
+    }
+
+    #[test]
+    fn save_two_layers() {
+        let data: Vec<f32> = (0..100).map(|i| i as f32).collect();
+        let network = "layer0 = [[1.0, 0.5], [0.0, 0.0]]\n\
+                       layer1 = [[1.0, 0.0], [0.0]]\n";
+        let file = model_file(network, &[0..50, 50..100]);
+        let model: ForwardingModel = ForwardingModel::read_toml(&file.path(), &data);
 
+        let saved = NamedTempFile::new().unwrap();
+        model.save(&saved.path()).unwrap();
+        let loaded: ForwardingModel = ForwardingModel::read_toml(&saved.path(), &data);
+        assert_eq!(loaded.net, model.net);
+        assert_eq!(loaded.btrees, model.btrees);
+        for (i, &key) in data.iter().enumerate() {
+            assert_eq!(loaded.eval(key), Some(i as u32));
+        }
     }
 }
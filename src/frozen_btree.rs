@@ -0,0 +1,397 @@
+//! A read-only B Tree packed into two flat arrays.
+//!
+//! `BTree` leaves room in every node for later inserts and keeps its child
+//! pointers in a separate array, neither of which a tree that will never
+//! change needs. A `FrozenBTree` has every node full, and lays the nodes out in
+//! breadth-first order as a complete tree with fanout `2 * T`, so the children
+//! of node `k` are the nodes `k * 2 * T + 1` through `k * 2 * T + 2 * T`, and
+//! there are no child pointers at all. The keys of all the nodes are stored
+//! together, so a search touches one run of `2 * T - 1` keys per level and
+//! only reads an index once it has found its answer.
+//!
+//! Every node has `2 * T - 1` slots, so unless the number of entries is a
+//! multiple of that there are spare slots. They are the last slots in key
+//! order, wherever those fall in the layout, and they're filled with copies of
+//! the largest entry, so they never change the result of a search.
+
+use std::iter;
+use std::mem;
+
+use btree::{prefetch, Stats, GROUP};
+use key::Key;
+use model::Model;
+use node_search::{Binary, NodeSearch};
+
+/// An immutable, fully packed B Tree with minimum degree `T`.
+///
+/// Build one with `BTree::freeze`, or directly from sorted data with
+/// `from_sorted` or `from_sorted_parts`. Entries with equal keys keep the
+/// order they had in the source, so `search` finds the first of them.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct FrozenBTree<K, I, const T: usize = 8> {
+    // `2 * T - 1` keys for each node, nodes in breadth-first order
+    keys: Box<[K]>,
+
+    // the index of each key in `keys`
+    indices: Box<[I]>,
+
+    // the number of entries, not counting the spare slots
+    len: usize,
+}
+
+impl<K, I, const T: usize> FrozenBTree<K, I, T>
+where
+    K: Key,
+    I: Copy + Default,
+{
+    /// Build a tree from `(key, index)` pairs sorted by key.
+    pub fn from_sorted(pairs: &[(K, I)]) -> Self {
+        Self::build_sorted(pairs.len(), |i| pairs[i])
+    }
+
+    /// Like `from_sorted`, but with the keys and indices in separate slices,
+    /// so that `indices[i]` is the index of `keys[i]`.
+    ///
+    /// Panics if the slices have different lengths.
+    pub fn from_sorted_parts(keys: &[K], indices: &[I]) -> Self {
+        assert_eq!(
+            keys.len(),
+            indices.len(),
+            "from_sorted_parts: keys and indices have different lengths"
+        );
+        Self::build_sorted(keys.len(), |i| (keys[i], indices[i]))
+    }
+
+    /// The number of entries in the tree.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the tree has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The entries in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (K, I)> + '_ {
+        let b = 2 * T - 1;
+        let node_count = self.keys.len() / b;
+
+        // the nodes from the root down to the next entry, each with the
+        // position of its next entry
+        let mut stack = Vec::new();
+        push_left(&mut stack, 0, node_count, b);
+        let mut remaining = self.len;
+        iter::from_fn(move || {
+            if remaining == 0 {
+                return None;
+            }
+            remaining -= 1;
+            let (k, i) = stack.pop().unwrap();
+            if i + 1 < b {
+                stack.push((k, i + 1));
+            }
+            push_left(&mut stack, k * (b + 1) + i + 2, node_count, b);
+            let slot = k * b + i;
+            Some((self.keys[slot], self.indices[slot]))
+        })
+    }
+
+    /// Find the first index with this key, or `None` if the key is not in
+    /// the tree.
+    pub fn search(&self, key: K) -> Option<I> {
+        self.search_with::<Binary>(key)
+    }
+
+    /// Search for each of `keys`, storing the result for `keys[i]` in
+    /// `indices[i]`.
+    ///
+    /// Like `BTree::search_many`, this runs a group of searches together a
+    /// level at a time, prefetching the node each will need next.
+    ///
+    /// Panics if the slices have different lengths.
+    pub fn search_many(&self, keys: &[K], indices: &mut [Option<I>]) {
+        self.search_many_with::<Binary>(keys, indices)
+    }
+
+    /// Like `search_many`, but using `S` to search within each node.
+    pub fn search_many_with<S>(&self, keys: &[K], indices: &mut [Option<I>])
+    where
+        S: NodeSearch<K>,
+    {
+        assert_eq!(
+            keys.len(),
+            indices.len(),
+            "search_many: keys and indices have different lengths"
+        );
+        for (keys, indices) in keys.chunks(GROUP).zip(indices.chunks_mut(GROUP)) {
+            self.search_group::<S>(keys, indices);
+        }
+    }
+
+    /// Like `search`, but using `S` to search within each node.
+    pub fn search_with<S>(&self, key: K) -> Option<I>
+    where
+        S: NodeSearch<K>,
+    {
+        match self.lower_bound_with::<S>(key) {
+            Some((k, index)) if k.key_eq(&key) => Some(index),
+            _ => None,
+        }
+    }
+
+    /// The first entry whose key is not less than `key`, or `None` if every
+    /// key in the tree is less than `key`.
+    pub fn lower_bound(&self, key: K) -> Option<(K, I)> {
+        self.lower_bound_with::<Binary>(key)
+    }
+
+    /// Like `lower_bound`, but using `S` to search within each node.
+    pub fn lower_bound_with<S>(&self, key: K) -> Option<(K, I)>
+    where
+        S: NodeSearch<K>,
+    {
+        self.descend(|keys| S::rank(keys, key))
+    }
+
+    /// The first entry whose key is greater than `key`, or `None` if no key in
+    /// the tree is greater than `key`.
+    pub fn upper_bound(&self, key: K) -> Option<(K, I)> {
+        self.descend(|keys| keys.iter().take_while(|k| k.key_le(&key)).count())
+    }
+
+    /// Summary statistics about the shape and size of the tree, in the same
+    /// terms as `BTree::stats`.
+    pub fn stats(&self) -> Stats {
+        let b = 2 * T - 1;
+        let node_count = self.keys.len() / b;
+        let mut height = 0;
+        let mut k = 0;
+        while k < node_count {
+            height += 1;
+            k = k * (b + 1) + 1;
+        }
+        Stats {
+            height,
+            node_count,
+            key_count: self.len,
+            fill_factor: if node_count == 0 {
+                0.0
+            } else {
+                self.len as f64 / self.keys.len() as f64
+            },
+            bytes_used: mem::size_of::<Self>()
+                + self.keys.len() * mem::size_of::<K>()
+                + self.indices.len() * mem::size_of::<I>(),
+        }
+    }
+
+    // Walk from the root to a leaf, using `rank` to choose the position in
+    // each node, and return the entry at the last such position that held
+    // one. Since the entries are sorted in order of an in-order traversal,
+    // that is the first entry, in key order, at any of the chosen positions.
+    fn descend<F>(&self, rank: F) -> Option<(K, I)>
+    where
+        F: Fn(&[K]) -> usize,
+    {
+        let b = 2 * T - 1;
+        let node_count = self.keys.len() / b;
+        let mut result = None;
+        let mut k = 0;
+        while k < node_count {
+            let i = rank(&self.keys[k * b..(k + 1) * b]);
+            if i < b {
+                result = Some(k * b + i);
+            }
+            k = k * (b + 1) + i + 1;
+        }
+        result.map(|slot| (self.keys[slot], self.indices[slot]))
+    }
+
+    // Search for up to `GROUP` keys together, as `descend` does for one. The
+    // tree is complete, so a search ends at the last level or the one above
+    // it, and the searches that end early just sit out the last level.
+    fn search_group<S>(&self, keys: &[K], indices: &mut [Option<I>])
+    where
+        S: NodeSearch<K>,
+    {
+        let b = 2 * T - 1;
+        let node_count = self.keys.len() / b;
+        let mut nodes = [0; GROUP];
+        let mut found = [None; GROUP];
+        let mut searching = true;
+        while searching {
+            searching = false;
+            for (j, &key) in keys.iter().enumerate() {
+                let k = nodes[j];
+                if k >= node_count {
+                    continue;
+                }
+                searching = true;
+                let i = S::rank(&self.keys[k * b..(k + 1) * b], key);
+                if i < b {
+                    found[j] = Some(k * b + i);
+                }
+                let child = k * (b + 1) + i + 1;
+                if child < node_count {
+                    prefetch(&self.keys[child * b..(child + 1) * b]);
+                }
+                nodes[j] = child;
+            }
+        }
+
+        for (j, (&key, index)) in keys.iter().zip(indices.iter_mut()).enumerate() {
+            *index = match found[j] {
+                Some(slot) if self.keys[slot].key_eq(&key) => Some(self.indices[slot]),
+                _ => None,
+            };
+        }
+    }
+
+    fn build_sorted<F>(n: usize, pair: F) -> Self
+    where
+        F: Fn(usize) -> (K, I),
+    {
+        assert!(T >= 2, "FrozenBTree: the minimum degree must be at least 2");
+        debug_assert!((1..n).all(|i| pair(i - 1).0.key_le(&pair(i).0)));
+
+        let b = 2 * T - 1;
+        let node_count = n.div_ceil(b);
+        let mut order = Vec::with_capacity(node_count * b);
+        in_order(0, node_count, b, &mut order);
+
+        let mut keys = vec![K::default(); node_count * b];
+        let mut indices = vec![I::default(); node_count * b];
+        for (i, &slot) in order.iter().enumerate() {
+            let (key, index) = pair(i.min(n - 1));
+            keys[slot] = key;
+            indices[slot] = index;
+        }
+
+        FrozenBTree {
+            keys: keys.into_boxed_slice(),
+            indices: indices.into_boxed_slice(),
+            len: n,
+        }
+    }
+}
+
+// Push the slots of the subtree rooted at node `k` onto `order`, in the order
+// an in-order traversal visits them.
+fn in_order(k: usize, node_count: usize, b: usize, order: &mut Vec<usize>) {
+    if k >= node_count {
+        return;
+    }
+    for i in 0..b {
+        in_order(k * (b + 1) + i + 1, node_count, b, order);
+        order.push(k * b + i);
+    }
+    in_order(k * (b + 1) + b + 1, node_count, b, order);
+}
+
+// Push node `k` and the first child of each node below it onto `stack`, each
+// at its first entry.
+fn push_left(stack: &mut Vec<(usize, usize)>, mut k: usize, node_count: usize, b: usize) {
+    while k < node_count {
+        stack.push((k, 0));
+        k = k * (b + 1) + 1;
+    }
+}
+
+impl<K, I, const T: usize> Model<K, I> for FrozenBTree<K, I, T>
+where
+    K: Key,
+    I: Copy + Default,
+{
+    fn eval(&self, key: K) -> Option<I> {
+        self.search(key)
+    }
+
+    fn eval_lower_bound(&self, key: K) -> Option<I> {
+        self.lower_bound(key).map(|(_, index)| index)
+    }
+
+    fn eval_upper_bound(&self, key: K) -> Option<I> {
+        self.upper_bound(key).map(|(_, index)| index)
+    }
+
+    fn eval_many(&self, keys: &[K], indices: &mut [Option<I>]) {
+        self.search_many(keys, indices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    use btree::BTree;
+    use node_search::{Linear, Simd};
+
+    fn against_btree<const T: usize>(n: usize, rng: &mut XorShiftRng) {
+        let b: BTree<u32, u32, T> = (0..n as u32)
+            .map(|i| (rng.gen_range(0, 2 * n as u32 + 1), i))
+            .collect();
+        let frozen = b.freeze();
+        assert_eq!(frozen.len(), n);
+        assert!(frozen.iter().eq(b.iter()));
+        for key in 0..2 * n as u32 + 3 {
+            assert_eq!(frozen.search(key), b.search(key), "{}", key);
+            assert_eq!(frozen.search_with::<Linear>(key), b.search(key));
+            assert_eq!(frozen.search_with::<Simd>(key), b.search(key));
+            assert_eq!(frozen.lower_bound(key), b.lower_bound(key), "{}", key);
+            assert_eq!(frozen.upper_bound(key), b.upper_bound(key), "{}", key);
+        }
+        let keys: Vec<u32> = (0..2 * n as u32 + 3).collect();
+        let mut expected = vec![None; keys.len()];
+        b.search_many(&keys, &mut expected);
+        let mut indices = vec![None; keys.len()];
+        frozen.eval_many(&keys, &mut indices);
+        assert_eq!(indices, expected);
+        frozen.search_many_with::<Simd>(&keys, &mut indices);
+        assert_eq!(indices, expected);
+    }
+
+    #[test]
+    fn matches_btree() {
+        let mut rng = XorShiftRng::from_seed([12; 16]);
+        for &n in [0, 1, 2, 3, 4, 5, 6, 7, 8, 50, 1000, 5000].iter() {
+            against_btree::<2>(n, &mut rng);
+            against_btree::<3>(n, &mut rng);
+            against_btree::<8>(n, &mut rng);
+        }
+    }
+
+    #[test]
+    fn from_sorted() {
+        let data: Vec<f32> = (0..3000).map(|i| (i / 3) as f32).collect();
+        let indices: Vec<u32> = (0..3000).collect();
+        let frozen: FrozenBTree<f32, u32> = FrozenBTree::from_sorted_parts(&data, &indices);
+        let pairs: Vec<(f32, u32)> = data.iter().cloned().zip(indices.iter().cloned()).collect();
+        assert_eq!(frozen, FrozenBTree::from_sorted(&pairs));
+        for i in 0..1000 {
+            assert_eq!(frozen.search(i as f32), Some(3 * i));
+            assert_eq!(
+                frozen.eval_upper_bound(i as f32),
+                Some(3 * i + 3).filter(|&j| j < 3000)
+            );
+            assert_eq!(frozen.search(i as f32 + 0.5), None);
+        }
+    }
+
+    #[test]
+    fn smaller() {
+        let b: BTree<f32, u32> = (0..10000)
+            .map(|i| (((i * 7919) % 10000) as f32, i))
+            .collect();
+        let frozen = b.freeze();
+        let sorted: BTree<f32, u32> = BTree::from_sorted(&b.iter().collect::<Vec<_>>());
+        let stats = frozen.stats();
+        assert_eq!(stats.key_count, 10000);
+        assert!(stats.fill_factor > 0.99);
+        assert!(stats.height <= sorted.stats().height);
+        assert!(stats.bytes_used < sorted.stats().bytes_used);
+        assert!(stats.bytes_used < b.stats().bytes_used);
+    }
+}
@@ -0,0 +1,177 @@
+//! Keys with a total order.
+//!
+//! A B Tree relies on its keys being totally ordered: if `a < b` and `b < c`
+//! then `a < c`, and any two keys are less, equal or greater. `PartialOrd` for
+//! floats breaks this, since NaN is neither less than, equal to nor greater
+//! than anything, so a single NaN key can send searches down the wrong subtree
+//! or make a sort panic. The trees in this crate instead compare keys through
+//! the `Key` trait. For `f32` and `f64` it uses IEEE 754's total order, the
+//! same as `f32::total_cmp`: negative NaNs sort before everything, then
+//! `-inf`, the negative numbers, `-0.0`, `0.0`, the positive numbers, `inf`,
+//! and last the positive NaNs.
+//!
+//! `-0.0` and `0.0` are different keys in that order, so data loaded from
+//! outside goes through `Key::canonicalize` first, which turns `-0.0` into
+//! `0.0` and rejects NaN. `ForwardingModel` passes the keys it's asked for
+//! through it too, so that a lookup of `-0.0` finds `0.0`.
+//!
+//! Keys are fed to the neural network in `ForwardingModel` as `f64`, through
+//! `Key::to_f64`. For large integers, such as `u64` timestamps, that loses
+//! precision, but the network only has to choose a B Tree, and the search in
+//! the B Tree compares the keys themselves, so lookups stay exact.
+//!
+//! The `Index` trait is for the positions keys map to, which are `u32` or,
+//! for more than 4 billion records, `u64`.
+
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
+
+/// A type that can be used as a key, with a total order.
+pub trait Key: Copy + Default {
+    /// Compare two keys. This must be a total order.
+    fn key_cmp(&self, other: &Self) -> Ordering;
+
+    /// Whether `self` comes before `other`.
+    fn key_lt(&self, other: &Self) -> bool {
+        self.key_cmp(other) == Ordering::Less
+    }
+
+    /// Whether `self` comes before `other` or is equal to it.
+    fn key_le(&self, other: &Self) -> bool {
+        self.key_cmp(other) != Ordering::Greater
+    }
+
+    /// Whether `self` and `other` are the same key.
+    fn key_eq(&self, other: &Self) -> bool {
+        self.key_cmp(other) == Ordering::Equal
+    }
+
+    /// The key in the form it should be stored in, or `None` if it shouldn't
+    /// be used as a key at all. Floats have `-0.0` replaced by `0.0`, and NaN
+    /// gives `None`. Other keys are returned unchanged.
+    fn canonicalize(self) -> Option<Self> {
+        Some(self)
+    }
+
+    /// The key as input to a neural network. This should be monotonic, but
+    /// need not be exact.
+    fn to_f64(self) -> f64;
+}
+
+/// A type that can be used as an index: the position of a key in the data.
+pub trait Index: Copy + Default + Ord + fmt::Debug {
+    /// Panics if `i` doesn't fit.
+    fn from_usize(i: usize) -> Self;
+
+    /// Panics if the index doesn't fit in a `usize`.
+    fn to_usize(self) -> usize;
+}
+
+macro_rules! impl_key_float {
+    ($($t:ty),*) => {
+        $(
+            impl Key for $t {
+                fn key_cmp(&self, other: &Self) -> Ordering {
+                    self.total_cmp(other)
+                }
+
+                fn canonicalize(self) -> Option<Self> {
+                    if self.is_nan() {
+                        None
+                    } else {
+                        // -0.0 + 0.0 is 0.0, and every other value is unchanged
+                        Some(self + 0.0)
+                    }
+                }
+
+                fn to_f64(self) -> f64 {
+                    self as f64
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! impl_key_int {
+    ($($t:ty),*) => {
+        $(
+            impl Key for $t {
+                fn key_cmp(&self, other: &Self) -> Ordering {
+                    self.cmp(other)
+                }
+
+                fn to_f64(self) -> f64 {
+                    self as f64
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! impl_index {
+    ($($t:ty),*) => {
+        $(
+            impl Index for $t {
+                fn from_usize(i: usize) -> Self {
+                    <$t>::try_from(i).expect("Index: index out of range")
+                }
+
+                fn to_usize(self) -> usize {
+                    usize::try_from(self).expect("Index: index out of range")
+                }
+            }
+        )*
+    };
+}
+
+impl_key_float!(f32, f64);
+impl_key_int!(u32, u64, i32, i64);
+impl_index!(u32, u64, usize);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::f32;
+
+    #[test]
+    fn float_order() {
+        let ordered = [
+            -f32::NAN,
+            f32::NEG_INFINITY,
+            -1.5,
+            -0.0,
+            0.0,
+            f32::MIN_POSITIVE,
+            1.5,
+            f32::INFINITY,
+            f32::NAN,
+        ];
+        for (i, a) in ordered.iter().enumerate() {
+            for (j, b) in ordered.iter().enumerate() {
+                assert_eq!(a.key_cmp(b), i.cmp(&j), "{} {}", a, b);
+            }
+        }
+        assert!(f32::NAN.key_eq(&f32::NAN));
+        assert!(!(-0.0f32).key_eq(&0.0));
+    }
+
+    #[test]
+    fn canonicalize() {
+        assert_eq!((-0.0f32).canonicalize().map(f32::to_bits), Some(0));
+        assert_eq!((-0.0f64).canonicalize().map(f64::to_bits), Some(0));
+        assert_eq!((-2.5f32).canonicalize(), Some(-2.5));
+        assert_eq!(f32::NAN.canonicalize(), None);
+        assert_eq!((-f32::NAN).canonicalize(), None);
+        assert_eq!(f64::NAN.canonicalize(), None);
+        assert_eq!(7u32.canonicalize(), Some(7));
+    }
+
+    #[test]
+    fn index() {
+        assert_eq!(u32::from_usize(7), 7);
+        assert_eq!(u64::from_usize(1 << 40).to_usize(), 1 << 40);
+        assert!(::std::panic::catch_unwind(|| u32::from_usize(1 << 40)).is_err());
+    }
+}
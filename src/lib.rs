@@ -2,11 +2,18 @@ extern crate rand;
 extern crate tempfile;
 extern crate toml;
 
+mod arena;
 pub mod bench;
+pub mod bplustree;
 pub mod btree;
+pub mod concurrent_btree;
+pub mod disk_btree;
 pub mod forwarding_model;
+pub mod frozen_btree;
+pub mod key;
 pub mod model;
 pub mod neural;
+pub mod node_search;
 pub mod synthetic;
 pub mod train;
 
@@ -17,16 +24,17 @@ mod tests {
     #[test]
     fn f() {
         let data = synthetic::gen_lognormal(10000);
-        let mut b: btree::BTree<f32, u32> = Default::default();
+        let indices: Vec<u32> = (0..data.len() as u32).collect();
+        let b: btree::BTree<f32, u32> = btree::BTree::from_sorted_parts(&data, &indices);
 
+        let mut first = 0;
         for (i, &v) in data.iter().enumerate() {
-            b.insert(v, i as u32);
-        }
-
-        for &v in data.iter() {
-            // we may not have the same index, in the case of duplicate values,
-            // but the value at that index will be the same
-            assert_eq!(data[b.search(v).unwrap() as usize], v);
+            // in the case of duplicate values, we get the earliest index
+            if data[first] != v {
+                first = i;
+            }
+            assert_eq!(b.search(v), Some(first as u32));
+            assert!(b.search_all(v).any(|j| j == i as u32));
         }
     }
 }
@@ -6,10 +6,34 @@ where
 {
     fn eval(&self, key: K) -> Option<I>;
 
+    /// The index of the first key not less than `key`, or `None` if every key
+    /// is less than `key`.
+    ///
+    /// Unlike `eval`, this gives a position for keys that aren't present.
+    ///
+    /// The default only finds keys that are present, with `eval`, and gives
+    /// `None` for the rest, so models that can place missing keys should
+    /// override it.
+    fn eval_lower_bound(&self, key: K) -> Option<I> {
+        self.eval(key)
+    }
+
+    /// The index of the first key greater than `key`, or `None` if no key is
+    /// greater than `key`.
+    ///
+    /// The default finds the lower bound of `key` if it isn't present, and
+    /// otherwise gives `None`, since `eval` says nothing about the keys after
+    /// it; models that know the order of their keys should override it.
+    fn eval_upper_bound(&self, key: K) -> Option<I> {
+        match self.eval(key) {
+            Some(_) => None,
+            None => self.eval_lower_bound(key),
+        }
+    }
+
     fn eval_many(&self, keys: &[K], indices: &mut [Option<I>]) {
         for (i, &key) in keys.iter().enumerate() {
             indices[i] = self.eval(key);
         }
     }
 }
-
@@ -1,41 +1,119 @@
-//! A fully connected neural network with Leaky ReLU activations.
+//! A fully connected neural network with an activation function for each
+//! layer.
 //!
-//! Not optimized at the moment, but at least there are fewer superfluous
-//! allocations now.
+//! The layers are computed with SIMD instructions where the CPU has them; see
+//! `Kernel`.
 
+use std::error::Error;
+use std::fmt;
 use std::mem;
 use std::ops::{Index, IndexMut};
-use std::slice;
 
+use toml::value::Table;
 use toml::Value;
 
-// since we will eventually need 32-byte aligned memory for AVX instructions, we
-// have to jump through some hoops to allocate and deallocate
+mod aligned;
+mod kernel;
+pub mod train;
 
-#[repr(align(32))]
-struct Aligned(u8);
+pub use self::kernel::Kernel;
 
+use self::aligned::AlignedBuf;
+use self::kernel::Supported;
+
+// The slope of Leaky ReLU in Keras, and so in models saved before the model
+// file named the activations.
 const LEAKY_SLOPE: f32 = 0.3;
 
-fn ceil_div(dividend: usize, divisor: usize) -> usize {
-    (dividend + divisor - 1) / divisor
+/// The function applied to the output of a layer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Activation {
+    Identity,
+    Relu,
+    /// Leaky ReLU, multiplying negative values by the slope.
+    LeakyRelu(f32),
+    Tanh,
+    Sigmoid,
 }
 
-fn allocate_aligned_f32(len: usize) -> *mut f32 {
-    let aligned_len = ceil_div(4 * len, mem::align_of::<Aligned>());
-    let mut v: Vec<Aligned> = Vec::with_capacity(aligned_len);
-    let ptr = v.as_mut_ptr();
-    mem::forget(v);
-    unsafe { mem::transmute(ptr) }
-}
+impl Activation {
+    fn apply(self, kernel: Supported, values: &mut [f32]) {
+        use self::Activation::*;
+
+        match self {
+            Identity => {}
+            Relu => kernel.relu(values),
+            LeakyRelu(slope) => kernel.leaky_relu(values, slope),
+            Tanh => {
+                for value in values.iter_mut() {
+                    *value = value.tanh();
+                }
+            }
+            Sigmoid => {
+                for value in values.iter_mut() {
+                    *value = 1.0 / (1.0 + (-*value).exp());
+                }
+            }
+        }
+    }
+
+    /// Read an activation from the model file: one of the strings
+    /// `"linear"` (or `"identity"`), `"relu"`, `"leaky_relu"`, `"tanh"` and
+    /// `"sigmoid"`, or a table like `{ name = "leaky_relu", alpha = 0.1 }`
+    /// giving the slope of Leaky ReLU, which is otherwise 0.3.
+    fn from_toml(v: &Value) -> Self {
+        use self::Value::*;
+
+        let (name, alpha) = match v {
+            String(name) => (name, None),
+            Table(table) => match (table.get("name"), table.get("alpha")) {
+                (Some(String(name)), None) => (name, None),
+                (Some(String(name)), Some(&Float(alpha))) => (name, Some(alpha as f32)),
+                _ => panic!("Invalid activation: {}", v),
+            },
+            _ => panic!("Invalid activation: {}", v),
+        };
+        match (&name[..], alpha) {
+            ("linear", None) | ("identity", None) => Activation::Identity,
+            ("relu", None) => Activation::Relu,
+            ("leaky_relu", alpha) => Activation::LeakyRelu(alpha.unwrap_or(LEAKY_SLOPE)),
+            ("tanh", None) => Activation::Tanh,
+            ("sigmoid", None) => Activation::Sigmoid,
+            _ => panic!("Invalid activation: {}", v),
+        }
+    }
 
-fn deallocate_aligned_f32(ptr: *mut f32, len: usize) {
-    let aligned_len = ceil_div(len, mem::size_of::<Aligned>());
-    unsafe {
-        let _: Vec<Aligned> = Vec::from_raw_parts(mem::transmute(ptr), 0, aligned_len);
+    // The value `from_toml` reads as `self`, using the names Keras gives the
+    // activations, as `train.py` does.
+    fn to_toml(self) -> Value {
+        use self::Activation::*;
+
+        let name = match self {
+            Identity => "linear",
+            Relu => "relu",
+            LeakyRelu(slope) => {
+                let mut table = Table::new();
+                table.insert("name".to_string(), Value::from("leaky_relu"));
+                table.insert("alpha".to_string(), float(slope));
+                return Value::Table(table);
+            }
+            Tanh => "tanh",
+            Sigmoid => "sigmoid",
+        };
+        Value::from(name)
     }
 }
 
+// The shortest decimal that reads back as `x`, so `0.1` is written as `0.1`
+// rather than `0.10000000149011612`.
+fn float(x: f32) -> Value {
+    Value::Float(x.to_string().parse().unwrap())
+}
+
+fn float_array(values: &[f32]) -> Value {
+    Value::Array(values.iter().map(|&x| float(x)).collect())
+}
+
 fn value_array_arrays_float(v: &Value) -> Box<[Box<[f32]>]> {
     use self::Value::*;
 
@@ -56,104 +134,93 @@ fn value_array_arrays_float(v: &Value) -> Box<[Box<[f32]>]> {
                 panic!("Invalid TOML format");
             }
         }
-        return arrays.into_boxed_slice();
+        arrays.into_boxed_slice()
     } else {
         panic!("Invalid TOML format");
     }
 }
 
-#[repr(C)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct FirstLayer {
-    data: *mut f32,
-    bias: *mut f32,
-    size: usize,
+    data: AlignedBuf,
+    bias: AlignedBuf,
+    activation: Activation,
 }
 
 impl Index<usize> for FirstLayer {
     type Output = f32;
 
     fn index(&self, i: usize) -> &f32 {
-        if self.size <= i {
-            panic!("FirstLayer: index out of bounds");
-        } else {
-            unsafe { &*self.data.offset(i as isize) }
-        }
+        &self.data[i]
     }
 }
 
 impl IndexMut<usize> for FirstLayer {
     fn index_mut(&mut self, i: usize) -> &mut f32 {
-        if self.size <= i {
-            panic!("FirstLayer: index out of bounds");
-        } else {
-            unsafe { &mut *self.data.offset(i as isize) }
-        }
+        &mut self.data[i]
     }
 }
 
 impl FirstLayer {
     pub fn new(size: usize) -> Self {
         FirstLayer {
-            data: allocate_aligned_f32(size),
-            bias: allocate_aligned_f32(size),
-            size,
+            data: AlignedBuf::zeroed(size),
+            bias: AlignedBuf::zeroed(size),
+            activation: Activation::LeakyRelu(LEAKY_SLOPE),
         }
     }
 
-    fn bias(&self) -> &[f32] {
-        unsafe { slice::from_raw_parts(self.bias, self.size) }
+    fn size(&self) -> usize {
+        self.data.len()
     }
 
-    fn bias_mut(&mut self) -> &mut [f32] {
-        unsafe { slice::from_raw_parts_mut(self.bias, self.size) }
+    fn weights(&self) -> &[f32] {
+        &self.data
+    }
+
+    fn bias(&self) -> &[f32] {
+        &self.bias
     }
-}
 
-impl Drop for FirstLayer {
-    fn drop(&mut self) {
-        deallocate_aligned_f32(self.data, self.size);
-        deallocate_aligned_f32(self.bias, self.size);
+    fn bias_mut(&mut self) -> &mut [f32] {
+        &mut self.bias
     }
 }
 
-#[repr(C)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct LastLayer {
-    data: *mut f32,
-    size: usize,
+    data: AlignedBuf,
     bias: f32,
+    activation: Activation,
 }
 
 impl Index<usize> for LastLayer {
     type Output = f32;
 
     fn index(&self, i: usize) -> &f32 {
-        if self.size <= i {
-            panic!("FirstLayer: index out of bounds");
-        } else {
-            unsafe { &*self.data.offset(i as isize) }
-        }
+        &self.data[i]
     }
 }
 
 impl IndexMut<usize> for LastLayer {
     fn index_mut(&mut self, i: usize) -> &mut f32 {
-        if self.size <= i {
-            panic!("FirstLayer: index too small");
-        } else {
-            unsafe { &mut *self.data.offset(i as isize) }
-        }
+        &mut self.data[i]
     }
 }
 
 impl LastLayer {
     pub fn new(size: usize) -> Self {
         LastLayer {
-            data: allocate_aligned_f32(size),
+            data: AlignedBuf::zeroed(size),
             bias: 0.0,
-            size,
+            activation: Activation::Identity,
         }
     }
 
+    fn weights(&self) -> &[f32] {
+        &self.data
+    }
+
     fn bias(&self) -> &f32 {
         &self.bias
     }
@@ -163,16 +230,12 @@ impl LastLayer {
     }
 }
 
-impl Drop for LastLayer {
-    fn drop(&mut self) {
-        deallocate_aligned_f32(self.data, self.size);
-    }
-}
-
-#[repr(C)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct InteriorLayer {
-    data: *mut f32,
-    bias: *mut f32,
+    // the matrix, stored by rows
+    data: AlignedBuf,
+    bias: AlignedBuf,
+    activation: Activation,
     rows: usize,
     columns: usize,
 }
@@ -180,26 +243,24 @@ pub struct InteriorLayer {
 impl InteriorLayer {
     fn new(rows: usize, columns: usize) -> Self {
         InteriorLayer {
-            data: allocate_aligned_f32(rows * columns),
-            bias: allocate_aligned_f32(rows),
+            data: AlignedBuf::zeroed(rows * columns),
+            bias: AlignedBuf::zeroed(rows),
+            activation: Activation::LeakyRelu(LEAKY_SLOPE),
             rows,
             columns,
         }
     }
 
-    fn bias(&self) -> &[f32] {
-        unsafe { slice::from_raw_parts(self.bias, self.rows) }
+    fn weights(&self) -> &[f32] {
+        &self.data
     }
 
-    fn bias_mut(&mut self) -> &mut [f32] {
-        unsafe { slice::from_raw_parts_mut(self.bias, self.rows) }
+    fn bias(&self) -> &[f32] {
+        &self.bias
     }
-}
 
-impl Drop for InteriorLayer {
-    fn drop(&mut self) {
-        deallocate_aligned_f32(self.data, self.rows * self.columns);
-        deallocate_aligned_f32(self.data, self.rows);
+    fn bias_mut(&mut self) -> &mut [f32] {
+        &mut self.bias
     }
 }
 
@@ -209,7 +270,7 @@ impl Index<(usize, usize)> for InteriorLayer {
         if i.0 >= self.rows || i.1 >= self.columns {
             panic!("InteriorLayer: index out of bounds")
         } else {
-            unsafe { &*self.data.offset((self.columns * i.0 + i.1) as isize) }
+            &self.data[self.columns * i.0 + i.1]
         }
     }
 }
@@ -219,78 +280,163 @@ impl IndexMut<(usize, usize)> for InteriorLayer {
         if i.0 >= self.rows || i.1 >= self.columns {
             panic!("InteriorLayer: index out of bounds")
         } else {
-            unsafe { &mut *self.data.offset((self.columns * i.0 + i.1) as isize) }
+            &mut self.data[self.columns * i.0 + i.1]
         }
     }
 }
 
+/// Scratch space for `Network::apply_batch`.
+///
+/// It grows to fit the networks it is used with, so reusing one for many
+/// calls saves allocating each time.
+#[derive(Clone, Debug, Default)]
+pub struct Scratch {
+    buf1: Vec<f32>,
+    buf2: Vec<f32>,
+}
+
+impl Scratch {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+// The number of inputs `apply_batch` runs through the network together. The
+// values of the widest layer for this many inputs should fit in the L1 cache.
+const BLOCK: usize = 64;
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Network {
     first_layer: FirstLayer,
     last_layer: LastLayer,
     interior_layers: Box<[InteriorLayer]>,
+
+    // the kernels for `apply_buffer` and `apply_batch`
+    kernel: Supported,
+    batch_kernel: Supported,
 }
 
 impl Network {
-    pub fn apply_buffer(&self, x: f32, buf1: &mut [f32], buf2: &mut [f32]) -> f32 {
-        // first layer
-        debug_assert!(buf1.len() >= self.first_layer.size);
-        for i in 0..self.first_layer.size {
-            buf1[i] = x * self.first_layer[i] + self.first_layer.bias()[i];
-            if buf1[i] < 0.0 {
-                buf1[i] *= LEAKY_SLOPE;
-            }
-        }
+    /// The instruction set `apply_buffer` uses to compute the layers. This
+    /// starts as `Kernel::detect_single()`.
+    pub fn kernel(&self) -> Kernel {
+        self.kernel.kernel()
+    }
 
-        // interior layers
-        fn write_layer(
-            layers: &[InteriorLayer],
-            layer_index: usize,
-            read: &mut [f32],
-            write: &mut [f32],
-        ) {
-            if layer_index >= layers.len() {
-                return;
-            }
-            let layer = &layers[layer_index];
-            debug_assert!(read.len() >= layer.columns);
-            debug_assert!(write.len() >= layer.rows);
-            for row in 0..layer.rows {
-                write[row] = 0.0;
-                for col in 0..layer.columns {
-                    write[row] += layer[(row, col)] * read[col];
-                }
-                write[row] += layer.bias()[row];
-                if write[row] < 0.0 {
-                    write[row] *= LEAKY_SLOPE;
-                }
-            }
-            write_layer(layers, layer_index + 1, write, read);
-        }
+    /// The instruction set `apply_batch` uses to compute the layers. This
+    /// starts as `Kernel::detect()`, the fastest one the CPU supports.
+    pub fn batch_kernel(&self) -> Kernel {
+        self.batch_kernel.kernel()
+    }
+
+    /// Compute the layers with `kernel` from now on, in both `apply_buffer`
+    /// and `apply_batch`.
+    ///
+    /// Panics if the CPU doesn't support `kernel`.
+    pub fn set_kernel(&mut self, kernel: Kernel) {
+        self.kernel = Supported::new(kernel)
+            .unwrap_or_else(|| panic!("Network: this CPU doesn't support {:?}", kernel));
+        self.batch_kernel = self.kernel;
+    }
 
-        write_layer(&self.interior_layers, 0, buf1, buf2);
+    /// Run the network on the input `x`, using `buf1` and `buf2`, which must
+    /// each hold at least `buf_size()` values, as scratch space.
+    ///
+    /// The input is an `f64` so that large integer keys keep their precision
+    /// through the first layer, which is computed in `f64`; the rest of the
+    /// network uses `f32`.
+    pub fn apply_buffer(&self, x: f64, buf1: &mut [f32], buf2: &mut [f32]) -> f32 {
+        let kernel = self.kernel;
 
-        let mut result = 0.0f32;
+        // first layer
+        let size = self.first_layer.size();
+        let first = &mut buf1[..size];
+        kernel.first_layer(x, self.first_layer.weights(), self.first_layer.bias(), first);
+        self.first_layer.activation.apply(kernel, first);
+
+        // interior layers, each reading the output of the one before and
+        // writing to the other buffer
+        let mut read: &mut [f32] = buf1;
+        let mut write: &mut [f32] = buf2;
+        let mut columns = size;
+        for layer in self.interior_layers.iter() {
+            debug_assert_eq!(layer.columns, columns);
+            let out = &mut write[..layer.rows];
+            kernel.matrix_vector(layer.weights(), layer.bias(), &read[..columns], out);
+            layer.activation.apply(kernel, out);
+            mem::swap(&mut read, &mut write);
+            columns = layer.rows;
+        }
 
         // last layer
-        let read = if self.interior_layers.len() % 2 == 0 {
-            buf1
-        } else {
-            buf2
-        };
+        let mut result = [kernel.dot(self.last_layer.weights(), &read[..columns])];
+        result[0] += *self.last_layer.bias();
+        self.last_layer.activation.apply(kernel, &mut result);
 
-        debug_assert!(read.len() >= self.last_layer.size);
+        result[0]
+    }
 
-        for row in 0..self.last_layer.size {
-            result += self.last_layer[row] * read[row];
+    /// Run the network on each of `keys`, storing the result for `keys[i]` in
+    /// `out[i]`.
+    ///
+    /// This gives the same results as calling `apply_buffer` for each key, up
+    /// to rounding, but is much faster for more than a few keys: the keys are
+    /// run through the network in blocks, so each interior layer is a product
+    /// of two matrices instead of a matrix and a vector, and each weight is
+    /// loaded once per block rather than once per key.
+    ///
+    /// Panics if the slices have different lengths.
+    pub fn apply_batch(&self, keys: &[f64], out: &mut [f32], scratch: &mut Scratch) {
+        assert_eq!(
+            keys.len(),
+            out.len(),
+            "apply_batch: keys and out have different lengths"
+        );
+        let size = self.buf_size() * BLOCK;
+        if scratch.buf1.len() < size {
+            scratch.buf1.resize(size, 0.0);
+            scratch.buf2.resize(size, 0.0);
+        }
+        for (keys, out) in keys.chunks(BLOCK).zip(out.chunks_mut(BLOCK)) {
+            self.apply_block(keys, out, &mut scratch.buf1, &mut scratch.buf2);
         }
+    }
 
-        result += *self.last_layer.bias();
+    // One block of `apply_batch`. The values of each layer are stored as a
+    // matrix with a row for each unit and a column for each key.
+    fn apply_block(&self, keys: &[f64], out: &mut [f32], buf1: &mut [f32], buf2: &mut [f32]) {
+        let kernel = self.batch_kernel;
+        let n = keys.len();
+
+        // first layer, computed in `f64` as in `apply_buffer`
+        let size = self.first_layer.size();
+        let first = &mut buf1[..size * n];
+        let weights = self.first_layer.weights().iter();
+        let bias = self.first_layer.bias().iter();
+        for (row, (&w, &b)) in first.chunks_exact_mut(n).zip(weights.zip(bias)) {
+            for (value, &x) in row.iter_mut().zip(keys.iter()) {
+                *value = (x * w as f64 + b as f64) as f32;
+            }
+        }
+        self.first_layer.activation.apply(kernel, first);
 
-        if result < 0.0 {
-            result *= LEAKY_SLOPE;
+        // interior layers
+        let mut read: &mut [f32] = buf1;
+        let mut write: &mut [f32] = buf2;
+        let mut columns = size;
+        for layer in self.interior_layers.iter() {
+            debug_assert_eq!(layer.columns, columns);
+            let values = &mut write[..layer.rows * n];
+            kernel.matrix_matrix(layer.weights(), layer.bias(), &read[..columns * n], n, values);
+            layer.activation.apply(kernel, values);
+            mem::swap(&mut read, &mut write);
+            columns = layer.rows;
         }
 
-        result
+        // last layer
+        let bias = [*self.last_layer.bias()];
+        kernel.matrix_matrix(self.last_layer.weights(), &bias, &read[..columns * n], n, out);
+        self.last_layer.activation.apply(kernel, out);
     }
 
     /// What size of buffer is necessary to pass to `apply_buffer`?
@@ -298,7 +444,7 @@ impl Network {
         use std::cmp::max;
 
         let mut bufsize = 0usize;
-        bufsize = max(bufsize, self.first_layer.size);
+        bufsize = max(bufsize, self.first_layer.size());
         for layer in self.interior_layers.iter() {
             bufsize = max(bufsize, layer.rows);
         }
@@ -308,8 +454,13 @@ impl Network {
 
     /// Create a Network from a TOML value in my custom format.
     ///
-    /// This is the only way to create a Network outside this module at the
-    /// moment.
+    /// Layer `i` is given by `layer{i}`, and its activation by
+    /// `activation{i}`, in the format read by `Activation::from_toml`. Without
+    /// one, the output layer is linear and the others use Leaky ReLU, as
+    /// `train.py` used to build them.
+    ///
+    /// Panics if the value isn't in that format. To build a Network from
+    /// weights in memory, use `NetworkBuilder`.
     pub fn from_toml(v: &Value) -> Self {
         use self::Value::*;
 
@@ -319,101 +470,287 @@ impl Network {
             panic!("Bad TOML format");
         };
 
-        let mut last_layer_index = 0usize;
-        for i in 0.. {
-            let layer_var = format!("layer{}", i);
-            if let Some(_layer) = table.get(&layer_var) {
-                last_layer_index = i;
-            } else {
-                break;
+        let layer_count = (0..)
+            .take_while(|i| table.contains_key(&format!("layer{}", i)))
+            .count();
+
+        // each layer lists its weights, by rows, and then its biases; the
+        // number of columns is the number of rows of the layer before
+        let mut builder = NetworkBuilder::new();
+        let mut columns = 1;
+        for layer_index in 0..layer_count {
+            let layer_toml = &table[&format!("layer{}", layer_index)];
+            let arrays = value_array_arrays_float(layer_toml);
+            if arrays.len() != 2 {
+                panic!("Bad TOML format");
             }
-        }
 
-        if last_layer_index < 2 {
-            panic!("Need at least two layers")
-        }
+            if columns == 0 || !arrays[0].len().is_multiple_of(columns) {
+                panic!("Invalid layer sizes: layer {}", layer_index);
+            }
+            let rows = arrays[0].len() / columns;
 
-        // first layer
+            let activation = match table.get(&format!("activation{}", layer_index)) {
+                Some(activation) => Activation::from_toml(activation),
+                None if layer_index + 1 == layer_count => Activation::Identity,
+                None => Activation::LeakyRelu(LEAKY_SLOPE),
+            };
 
-        let first_layer_toml = if let Some(layer) = table.get("layer0") {
-            layer
-        } else {
-            panic!("Bad TOML format");
-        };
+            builder = builder.layer(rows, columns, &arrays[0], &arrays[1], activation);
+            columns = rows;
+        }
 
-        let arrays = value_array_arrays_float(first_layer_toml);
+        builder
+            .build()
+            .unwrap_or_else(|e| panic!("Invalid network: {}", e))
+    }
 
-        let mut first_layer = FirstLayer::new(arrays[0].len());
+    /// The network in the format `from_toml` reads, which is the one
+    /// `train.py` saves, with the activation of every layer given.
+    ///
+    /// The weights are written exactly, so `from_toml` gives back an equal
+    /// network.
+    pub fn to_toml(&self) -> Value {
+        let mut table = Table::new();
+        let mut add_layer = |weights: &[f32], bias: &[f32], activation: Activation| {
+            let i = table.len() / 2;
+            let layer = Value::Array(vec![float_array(weights), float_array(bias)]);
+            table.insert(format!("layer{}", i), layer);
+            table.insert(format!("activation{}", i), activation.to_toml());
+        };
 
-        unsafe {
-            slice::from_raw_parts_mut(first_layer.data, first_layer.size)
-                .copy_from_slice(&arrays[0]);
+        let first = &self.first_layer;
+        add_layer(first.weights(), first.bias(), first.activation);
+        for layer in self.interior_layers.iter() {
+            add_layer(layer.weights(), layer.bias(), layer.activation);
         }
-        first_layer.bias_mut().copy_from_slice(&arrays[1]);
-
-        // interior layers
-
-        let mut interior_layers = Vec::new();
+        let last = &self.last_layer;
+        add_layer(last.weights(), &[*last.bias()], last.activation);
 
-        let mut previous_layer_rows = first_layer.size;
+        Value::Table(table)
+    }
+}
 
-        for layer_index in 1..last_layer_index {
-            let layer_toml = if let Some(layer) = table.get(&format!("layer{}", layer_index)) {
-                layer
-            } else {
-                unreachable!();
-            };
+/// Builds a `Network` from the weights of each of its layers.
+///
+/// Each layer has a row for each of its outputs and a column for each of its
+/// inputs, which are the outputs of the layer before it, or the network's
+/// single input for the first layer. Layers are added in order from the first
+/// layer to the output layer, which has a single row.
+///
+/// ```
+/// use learned_index_structures::neural::{Activation, NetworkBuilder};
+///
+/// let network = NetworkBuilder::new()
+///     .layer(2, 1, &[1.0, -1.0], &[0.0, 0.0], Activation::Relu)
+///     .layer(1, 2, &[2.0, 3.0], &[1.0], Activation::Identity)
+///     .build()
+///     .unwrap();
+/// let (mut buf1, mut buf2) = (vec![0.0; 2], vec![0.0; 2]);
+/// assert_eq!(network.apply_buffer(-1.0, &mut buf1, &mut buf2), 4.0);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct NetworkBuilder {
+    layers: Vec<LayerSpec>,
+}
 
-            let arrays = value_array_arrays_float(layer_toml);
+#[derive(Clone, Debug)]
+struct LayerSpec {
+    rows: usize,
+    columns: usize,
+    weights: Vec<f32>,
+    bias: Vec<f32>,
+    activation: Activation,
+}
 
-            if arrays[0].len() % previous_layer_rows != 0 {
-                panic!("Invalid layer sizes: layer {}", layer_index);
-            }
+impl NetworkBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
 
-            let columns = previous_layer_rows;
-            let rows = arrays[0].len() / previous_layer_rows;
+    /// Add a layer with `rows` outputs and `columns` inputs. `weights` holds
+    /// the matrix by rows, and `bias` one value for each row.
+    pub fn layer(
+        mut self,
+        rows: usize,
+        columns: usize,
+        weights: &[f32],
+        bias: &[f32],
+        activation: Activation,
+    ) -> Self {
+        self.layers.push(LayerSpec {
+            rows,
+            columns,
+            weights: weights.to_vec(),
+            bias: bias.to_vec(),
+            activation,
+        });
+        self
+    }
 
-            let mut layer = InteriorLayer::new(rows, columns);
+    /// Check that the layers fit together and build the network.
+    pub fn build(self) -> Result<Network, BuildError> {
+        use self::BuildError::*;
 
-            unsafe {
-                slice::from_raw_parts_mut(layer.data, rows * columns).copy_from_slice(&arrays[0]);
+        let count = self.layers.len();
+        if count < 2 {
+            return Err(TooFewLayers { count });
+        }
+        let mut inputs = 1;
+        for (layer, spec) in self.layers.iter().enumerate() {
+            if spec.columns != inputs {
+                return Err(Columns {
+                    layer,
+                    columns: spec.columns,
+                    expected: inputs,
+                });
             }
-            layer.bias_mut().copy_from_slice(&arrays[1]);
-
-            interior_layers.push(layer);
-
-            previous_layer_rows = rows;
+            if spec.weights.len() != spec.rows * spec.columns {
+                return Err(Weights {
+                    layer,
+                    len: spec.weights.len(),
+                    expected: spec.rows * spec.columns,
+                });
+            }
+            if spec.bias.len() != spec.rows {
+                return Err(Bias {
+                    layer,
+                    len: spec.bias.len(),
+                    expected: spec.rows,
+                });
+            }
+            inputs = spec.rows;
         }
-
-        // last layer
-
-        let last_layer_toml = if let Some(layer) = table.get(&format!("layer{}", last_layer_index))
-        {
-            layer
-        } else {
-            panic!("Bad TOML format");
-        };
-
-        let arrays = value_array_arrays_float(last_layer_toml);
-
-        let mut last_layer = LastLayer::new(arrays[0].len());
-        unsafe {
-            slice::from_raw_parts_mut(last_layer.data, last_layer.size).copy_from_slice(&arrays[0]);
+        if inputs != 1 {
+            return Err(Outputs { rows: inputs });
         }
-        *last_layer.bias_mut() = arrays[1][0];
 
-        Network {
+        let mut layers = self.layers.into_iter();
+
+        let spec = layers.next().unwrap();
+        let mut first_layer = FirstLayer::new(spec.rows);
+        first_layer.data.copy_from_slice(&spec.weights);
+        first_layer.bias_mut().copy_from_slice(&spec.bias);
+        first_layer.activation = spec.activation;
+
+        let spec = layers.next_back().unwrap();
+        let mut last_layer = LastLayer::new(spec.columns);
+        last_layer.data.copy_from_slice(&spec.weights);
+        *last_layer.bias_mut() = spec.bias[0];
+        last_layer.activation = spec.activation;
+
+        let interior_layers: Vec<_> = layers
+            .map(|spec| {
+                let mut layer = InteriorLayer::new(spec.rows, spec.columns);
+                layer.data.copy_from_slice(&spec.weights);
+                layer.bias_mut().copy_from_slice(&spec.bias);
+                layer.activation = spec.activation;
+                layer
+            })
+            .collect();
+
+        Ok(Network {
             first_layer,
             last_layer,
             interior_layers: interior_layers.into_boxed_slice(),
+            kernel: Supported::detect_single(),
+            batch_kernel: Supported::detect(),
+        })
+    }
+}
+
+/// Why `NetworkBuilder::build` couldn't build a network.
+///
+/// Layers are numbered from 0, in the order they were added.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum BuildError {
+    /// A network needs a first layer and an output layer.
+    TooFewLayers { count: usize },
+
+    /// A layer's number of columns isn't the number of rows of the layer
+    /// before it, or 1 for the first layer.
+    Columns {
+        layer: usize,
+        columns: usize,
+        expected: usize,
+    },
+
+    /// A layer has the wrong number of weights for its shape.
+    Weights {
+        layer: usize,
+        len: usize,
+        expected: usize,
+    },
+
+    /// A layer has the wrong number of biases for its shape.
+    Bias {
+        layer: usize,
+        len: usize,
+        expected: usize,
+    },
+
+    /// The output layer has more or fewer than one row.
+    Outputs { rows: usize },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::BuildError::*;
+
+        match *self {
+            TooFewLayers { count } => write!(f, "{} layers, but at least 2 are needed", count),
+            Columns {
+                layer,
+                columns,
+                expected,
+            } => write!(
+                f,
+                "layer {} has {} columns rather than {}",
+                layer, columns, expected
+            ),
+            Weights {
+                layer,
+                len,
+                expected,
+            } => write!(
+                f,
+                "layer {} has {} weights rather than {}",
+                layer, len, expected
+            ),
+            Bias {
+                layer,
+                len,
+                expected,
+            } => write!(
+                f,
+                "layer {} has {} biases rather than {}",
+                layer, len, expected
+            ),
+            Outputs { rows } => write!(f, "the output layer has {} rows rather than 1", rows),
         }
     }
 }
 
+impl Error for BuildError {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    // A network with layers 5, 2, 2 and 1 wide, with `activations` added to
+    // its TOML.
+    fn wide(activations: &str) -> Network {
+        let toml = format!(
+            "layer0 = [[0.5, -1.0, 2.0, 0.25, -0.5], [0.1, 0.2, -0.3, 0.4, 0.0]]\n\
+             layer1 = [[0.5, -0.5, 1.0, 0.0, 2.0, -1.0, 0.25, 0.5, -0.25, 1.5], \
+                       [0.5, -1.0]]\n\
+             layer2 = [[1.0, 2.0, -3.0, 0.5], [0.0, 0.5]]\n\
+             layer3 = [[3.0, -1.0], [0.5]]\n{}",
+            activations
+        );
+        Network::from_toml(&toml.parse().unwrap())
+    }
+
     #[test]
     fn f() {
         let mut first = FirstLayer::new(2);
@@ -439,6 +776,8 @@ mod tests {
             first_layer: first,
             interior_layers: vec![interior].into_boxed_slice(),
             last_layer: last,
+            kernel: Supported::detect_single(),
+            batch_kernel: Supported::detect(),
         };
 
         let mut buf1 = vec![0.0, 0.0];
@@ -450,4 +789,245 @@ mod tests {
 
         assert!((result - GOLDEN).abs() < 0.0001);
     }
+
+    #[test]
+    fn load_and_drop() {
+        // dropping a network used to free memory twice, and with the wrong
+        // size; run this under Miri to check the allocations
+        let toml: Value = "layer0 = [[1.0, 2.0, -1.0], [0.0, -3.0, 1.0]]\n\
+                           layer1 = [[1.0, 0.5, 0.0, -1.0, 2.0, 1.0], [0.0, 1.0]]\n\
+                           layer2 = [[1.0, 1.0, 0.5, 0.5], [0.0, 0.0]]\n\
+                           layer3 = [[-1.0, 2.0], [1.0]]\n"
+            .parse()
+            .unwrap();
+        let mut buf1 = vec![0.0; 3];
+        let mut buf2 = vec![0.0; 3];
+        for _ in 0..10 {
+            let mut network = Network::from_toml(&toml);
+            network.set_kernel(Kernel::Scalar);
+            let copy = network.clone();
+            drop(network);
+            assert_eq!(copy.buf_size(), 3);
+            // the layers give [1.0, -0.3, 0.0], [0.85, -0.18], [0.67, 0.335]
+            let result = copy.apply_buffer(1.0, &mut buf1, &mut buf2);
+            assert!((result - 1.0).abs() < 1e-5, "{}", result);
+        }
+    }
+
+    #[test]
+    fn activations() {
+        fn sigmoid(x: f32) -> f32 {
+            1.0 / (1.0 + (-x).exp())
+        }
+        // the first layer gives `[x, -x]`, the second passes that on, and the
+        // last gives the first value minus twice the second
+        let cases = [
+            ("", [2.36, -4.18]),
+            (
+                "activation0 = \"relu\"\n\
+                 activation1 = \"identity\"\n\
+                 activation2 = \"tanh\"\n",
+                [2.0f32.tanh(), (-4.0f32).tanh()],
+            ),
+            (
+                "activation0 = { name = \"leaky_relu\", alpha = 0.5 }\n\
+                 activation1 = \"sigmoid\"\n\
+                 activation2 = \"linear\"\n",
+                [
+                    sigmoid(2.0) - 2.0 * sigmoid(-1.0),
+                    sigmoid(-1.0) - 2.0 * sigmoid(2.0),
+                ],
+            ),
+        ];
+        let mut scratch = Scratch::new();
+        for &(activations, expected) in cases.iter() {
+            let toml = format!(
+                "layer0 = [[1.0, -1.0], [0.0, 0.0]]\n\
+                 layer1 = [[1.0, 0.0, 0.0, 1.0], [0.0, 0.0]]\n\
+                 layer2 = [[1.0, -2.0], [0.0]]\n{}",
+                activations
+            );
+            let mut network = Network::from_toml(&toml.parse().unwrap());
+            let mut buf1 = vec![0.0; 2];
+            let mut buf2 = vec![0.0; 2];
+            for &kernel in Kernel::ALL.iter().filter(|k| k.is_supported()) {
+                network.set_kernel(kernel);
+                let mut out = [0.0; 2];
+                network.apply_batch(&[2.0, -2.0], &mut out, &mut scratch);
+                for (&x, (&e, &batch)) in [2.0, -2.0].iter().zip(expected.iter().zip(out.iter())) {
+                    let result = network.apply_buffer(x, &mut buf1, &mut buf2);
+                    assert!((result - e).abs() < 1e-5, "{} {} {:?}", result, e, kernel);
+                    assert!((batch - e).abs() < 1e-5, "{} {} {:?}", batch, e, kernel);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid activation")]
+    fn bad_activation() {
+        let toml = "layer0 = [[1.0], [0.0]]\n\
+                    layer1 = [[1.0], [0.0]]\n\
+                    layer2 = [[1.0], [0.0]]\n\
+                    activation1 = { name = \"relu\", alpha = 0.5 }\n";
+        Network::from_toml(&toml.parse().unwrap());
+    }
+
+    #[test]
+    fn builder() {
+        let toml = "layer0 = [[1.0, 2.0, -1.0], [0.0, -3.0, 1.0]]\n\
+                    layer1 = [[1.0, 0.5, 0.0, -1.0, 2.0, 1.0], [0.0, 1.0]]\n\
+                    layer2 = [[-1.0, 2.0], [1.0]]\n\
+                    activation1 = \"tanh\"\n";
+        let leaky = Activation::LeakyRelu(0.3);
+        let layers = || {
+            NetworkBuilder::new()
+                .layer(3, 1, &[1.0, 2.0, -1.0], &[0.0, -3.0, 1.0], leaky)
+                .layer(2, 3, &[1.0, 0.5, 0.0, -1.0, 2.0, 1.0], &[0.0, 1.0], Activation::Tanh)
+        };
+        let network = layers()
+            .layer(1, 2, &[-1.0, 2.0], &[1.0], Activation::Identity)
+            .build()
+            .unwrap();
+        assert_eq!(network, Network::from_toml(&toml.parse().unwrap()));
+
+        use self::BuildError::*;
+        let error = |builder: NetworkBuilder| builder.build().unwrap_err();
+        assert_eq!(error(NetworkBuilder::new()), TooFewLayers { count: 0 });
+        assert_eq!(
+            error(NetworkBuilder::new().layer(1, 1, &[1.0], &[0.0], leaky)),
+            TooFewLayers { count: 1 }
+        );
+        assert_eq!(
+            error(layers().layer(1, 3, &[1.0; 3], &[0.0], leaky)),
+            Columns {
+                layer: 2,
+                columns: 3,
+                expected: 2
+            }
+        );
+        assert_eq!(
+            error(layers().layer(1, 2, &[1.0; 3], &[0.0], leaky)),
+            Weights {
+                layer: 2,
+                len: 3,
+                expected: 2
+            }
+        );
+        assert_eq!(
+            error(layers().layer(1, 2, &[1.0; 2], &[], leaky)),
+            Bias {
+                layer: 2,
+                len: 0,
+                expected: 1
+            }
+        );
+        assert_eq!(
+            error(layers().layer(2, 2, &[1.0; 4], &[0.0; 2], leaky)),
+            Outputs { rows: 2 }
+        );
+        let first = NetworkBuilder::new().layer(2, 2, &[1.0; 4], &[0.0; 2], leaky);
+        assert_eq!(
+            error(first.layer(1, 2, &[1.0; 2], &[0.0], leaky)).to_string(),
+            "layer 0 has 2 columns rather than 1"
+        );
+    }
+
+    #[test]
+    fn to_toml() {
+        let network = wide(
+            "activation0 = \"sigmoid\"\n\
+             activation1 = { name = \"leaky_relu\", alpha = 0.1 }\n",
+        );
+        let saved = network.to_toml();
+        assert_eq!(saved["activation0"].as_str(), Some("sigmoid"));
+        assert_eq!(saved["activation1"]["alpha"].as_float(), Some(0.1));
+        assert_eq!(saved["activation2"]["name"].as_str(), Some("leaky_relu"));
+        assert_eq!(saved["activation2"]["alpha"].as_float(), Some(0.3));
+        assert_eq!(saved["activation3"].as_str(), Some("linear"));
+        assert_eq!(saved["layer0"][1][0].as_float(), Some(0.1));
+
+        let text = ::toml::to_string(&saved).unwrap();
+        let loaded = Network::from_toml(&text.parse().unwrap());
+        assert_eq!(loaded, network);
+        let mut buf1 = vec![0.0; network.buf_size()];
+        let mut buf2 = vec![0.0; network.buf_size()];
+        for &x in [-100.0, -2.5, -0.1, 0.0, 0.3, 1.0, 7.0, 1e6].iter() {
+            let expected = network.apply_buffer(x, &mut buf1, &mut buf2);
+            assert_eq!(loaded.apply_buffer(x, &mut buf1, &mut buf2), expected);
+        }
+    }
+
+    #[test]
+    fn two_layers() {
+        // the smallest network `NetworkBuilder` accepts, one hidden layer and
+        // the output layer
+        let toml = "layer0 = [[1.0, -1.0], [0.0, 0.5]]\n\
+                    layer1 = [[2.0, 3.0], [1.0]]\n";
+        let network = Network::from_toml(&toml.parse().unwrap());
+        let mut buf1 = vec![0.0; network.buf_size()];
+        let mut buf2 = vec![0.0; network.buf_size()];
+        // the hidden layer gives [-1.0 * 0.3, 1.5]
+        let result = network.apply_buffer(-1.0, &mut buf1, &mut buf2);
+        assert!((result - 4.9).abs() < 1e-5, "{}", result);
+    }
+
+    #[test]
+    fn to_toml_two_layers() {
+        let network = NetworkBuilder::new()
+            .layer(2, 1, &[1.0, -1.0], &[0.0, 0.5], Activation::Tanh)
+            .layer(1, 2, &[2.0, 3.0], &[1.0], Activation::Identity)
+            .build()
+            .unwrap();
+        let text = ::toml::to_string(&network.to_toml()).unwrap();
+        assert_eq!(Network::from_toml(&text.parse().unwrap()), network);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid network: 1 layers, but at least 2 are needed")]
+    fn one_layer() {
+        Network::from_toml(&"layer0 = [[1.0], [0.0]]\n".parse().unwrap());
+    }
+
+    #[test]
+    fn batch() {
+        let mut network = wide("");
+        let mut buf1 = vec![0.0; network.buf_size()];
+        let mut buf2 = vec![0.0; network.buf_size()];
+        let mut scratch = Scratch::new();
+        let keys: Vec<f64> = (0..300).map(|i| (i as f64 - 150.0) / 7.0).collect();
+        for &kernel in Kernel::ALL.iter().filter(|k| k.is_supported()) {
+            network.set_kernel(kernel);
+            for &n in [0, 1, 5, 64, 65, 300].iter() {
+                let mut out = vec![0.0; n];
+                network.apply_batch(&keys[..n], &mut out, &mut scratch);
+                for (&x, &result) in keys.iter().zip(out.iter()) {
+                    let expected = network.apply_buffer(x, &mut buf1, &mut buf2);
+                    assert!((result - expected).abs() <= 1e-5 * (1.0 + expected.abs()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn kernels() {
+        let mut network = wide("");
+        let mut buf1 = vec![0.0; network.buf_size()];
+        let mut buf2 = vec![0.0; network.buf_size()];
+        network.set_kernel(Kernel::Scalar);
+        let inputs = [-100.0, -2.5, -0.1, 0.0, 0.3, 1.0, 7.0, 1e6];
+        let expected: Vec<f32> = inputs
+            .iter()
+            .map(|&x| network.apply_buffer(x, &mut buf1, &mut buf2))
+            .collect();
+        for &kernel in Kernel::ALL.iter().filter(|k| k.is_supported()) {
+            network.set_kernel(kernel);
+            assert_eq!(network.kernel(), kernel);
+            assert_eq!(network.batch_kernel(), kernel);
+            for (&x, &e) in inputs.iter().zip(expected.iter()) {
+                let result = network.apply_buffer(x, &mut buf1, &mut buf2);
+                assert!((result - e).abs() <= 1e-5 * (1.0 + e.abs()), "{:?}", kernel);
+            }
+        }
+    }
 }
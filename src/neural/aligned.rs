@@ -0,0 +1,169 @@
+//! Heap buffers of `f32`s aligned for vector instructions.
+//!
+//! `Vec<f32>` only promises 4 byte alignment. The kernels use unaligned loads,
+//! so they are correct either way, but a vector load that straddles a cache
+//! line is slower, and a buffer starting on a cache line keeps every aligned
+//! vector within one. `AlignedBuf` allocates through `std::alloc` with a
+//! `Layout` giving that alignment, and frees with the same `Layout`.
+
+use std::alloc::{self, Layout};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::ptr::{self, NonNull};
+use std::slice;
+
+/// The alignment of every buffer, in bytes: a cache line, and the width of an
+/// AVX-512 vector.
+pub const ALIGN: usize = 64;
+
+/// A fixed length, zero initialized slice of `f32`s starting at a multiple of
+/// `ALIGN` bytes.
+pub struct AlignedBuf {
+    // dangling if `len` is 0, and otherwise allocated with `layout(len)`
+    ptr: NonNull<f32>,
+    len: usize,
+}
+
+fn layout(len: usize) -> Layout {
+    len.checked_mul(4)
+        .and_then(|size| Layout::from_size_align(size, ALIGN).ok())
+        .expect("AlignedBuf: too large")
+}
+
+impl AlignedBuf {
+    /// A buffer of `len` zeros.
+    pub fn zeroed(len: usize) -> Self {
+        if len == 0 {
+            // aligned but never dereferenced
+            let ptr = ptr::without_provenance_mut(ALIGN);
+            return AlignedBuf {
+                ptr: NonNull::new(ptr).unwrap(),
+                len,
+            };
+        }
+        let layout = layout(len);
+        // all zero bits is `0.0f32`
+        let ptr = unsafe { alloc::alloc_zeroed(layout) } as *mut f32;
+        match NonNull::new(ptr) {
+            Some(ptr) => AlignedBuf { ptr, len },
+            None => alloc::handle_alloc_error(layout),
+        }
+    }
+
+    /// A buffer holding a copy of `values`.
+    pub fn from_slice(values: &[f32]) -> Self {
+        let mut buf = Self::zeroed(values.len());
+        buf.copy_from_slice(values);
+        buf
+    }
+}
+
+// An `AlignedBuf` owns its allocation, as a `Box<[f32]>` would, and only hands
+// out references to it through `&self` and `&mut self`, so the borrow checker
+// keeps those from racing. Moving one to another thread moves the ownership,
+// and sharing `&AlignedBuf` between threads only shares `&[f32]`, which is
+// `Sync`.
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        if self.len != 0 {
+            unsafe { alloc::dealloc(self.ptr.as_ptr() as *mut u8, layout(self.len)) }
+        }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [f32];
+
+    fn deref(&self) -> &[f32] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [f32] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Clone for AlignedBuf {
+    fn clone(&self) -> Self {
+        Self::from_slice(self)
+    }
+}
+
+impl fmt::Debug for AlignedBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl PartialEq for AlignedBuf {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // These, and `neural::tests::load_and_drop`, are meant to be run under
+    // Miri, which checks that each allocation is freed once, with the layout
+    // it was allocated with, and never accessed out of bounds:
+    //
+    //     cargo +nightly miri test -- neural::aligned neural::tests::load_and_drop
+
+    #[test]
+    fn zeroed() {
+        for &len in [0, 1, 3, 16, 17, 100].iter() {
+            let mut buf = AlignedBuf::zeroed(len);
+            assert_eq!(buf.len(), len);
+            assert_eq!(buf.as_ptr() as usize % ALIGN, 0);
+            assert!(buf.iter().all(|&x| x == 0.0));
+            for (i, x) in buf.iter_mut().enumerate() {
+                *x = i as f32;
+            }
+            assert!(buf.iter().cloned().eq((0..len).map(|i| i as f32)));
+        }
+    }
+
+    #[test]
+    fn clone() {
+        let a = AlignedBuf::from_slice(&[1.0, 2.0, 3.0]);
+        let mut b = a.clone();
+        b[0] = 5.0;
+        assert_eq!(&*a, &[1.0, 2.0, 3.0]);
+        assert_eq!(&*b, &[5.0, 2.0, 3.0]);
+        assert!(a != b);
+        assert_eq!(AlignedBuf::zeroed(0), AlignedBuf::from_slice(&[]));
+        assert_eq!(format!("{:?}", a), "[1.0, 2.0, 3.0]");
+    }
+
+    #[test]
+    fn threads() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let buf = Arc::new(AlignedBuf::from_slice(&[1.0, 2.0, 3.0]));
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let buf = buf.clone();
+                thread::spawn(move || buf.iter().sum::<f32>() * i as f32)
+            })
+            .collect();
+        let sums: Vec<f32> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(sums, [0.0, 6.0, 12.0, 18.0]);
+
+        let mut buf = AlignedBuf::zeroed(5);
+        buf = thread::spawn(move || {
+            buf[4] = 1.0;
+            buf
+        })
+        .join()
+        .unwrap();
+        assert_eq!(&*buf, &[0.0, 0.0, 0.0, 0.0, 1.0]);
+    }
+}
@@ -0,0 +1,786 @@
+//! The arithmetic of a `Network`'s layers, with versions for several
+//! instruction sets.
+//!
+//! Each layer of the network is an affine map followed by an activation. The
+//! affine maps come in three shapes: the first layer scales and shifts a single
+//! input, computed in `f64` (see `Network::apply_buffer`); the interior layers
+//! multiply a matrix by a vector; and the last layer takes a dot product. The
+//! functions here compute these, and the ReLU and Leaky ReLU activations, with
+//! plain scalar code or with SSE2, AVX2 (with FMA) or AVX-512 instructions.
+//!
+//! `Network::apply_batch` runs the network on many inputs at once, which turns
+//! the matrix-vector products into matrix-matrix products. Those are computed
+//! a tile at a time: a few rows of the output by two vectors' worth of
+//! columns, summed in registers, so each weight loaded is used for a whole
+//! vector of inputs and each vector of inputs for several weights.
+//!
+//! The vector versions load and store with unaligned instructions, since the
+//! rows of an interior layer's matrix don't start on aligned addresses unless
+//! the number of columns happens to be a multiple of the vector width. Apart
+//! from that, they sum the products of a row in a different order than the
+//! scalar version, so results can differ in the last few bits. The first layer
+//! and the activation give exactly the scalar results.
+
+/// The instruction set used to compute a `Network`'s layers.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Kernel {
+    /// Plain scalar code, available everywhere.
+    Scalar,
+
+    /// 128 bit vectors, available on every x86-64 CPU.
+    Sse2,
+
+    /// 256 bit vectors, with fused multiply-add.
+    Avx2,
+
+    /// 512 bit vectors.
+    Avx512,
+}
+
+impl Kernel {
+    /// Every kernel, supported or not, from slowest to fastest.
+    pub const ALL: [Kernel; 4] = [Kernel::Scalar, Kernel::Sse2, Kernel::Avx2, Kernel::Avx512];
+
+    /// The fastest kernel this CPU supports, which `Network::apply_batch`
+    /// uses by default.
+    pub fn detect() -> Self {
+        *Self::ALL
+            .iter()
+            .rev()
+            .find(|kernel| kernel.is_supported())
+            .unwrap()
+    }
+
+    /// The kernel `Network::apply_buffer` uses by default, which is the same
+    /// as `detect` except that it prefers AVX2 to AVX-512.
+    ///
+    /// On one key at a time AVX-512 is slower than AVX2: the layers of the
+    /// networks `train.py` makes are only a couple of its vectors wide, so
+    /// most of the work goes into masked loads and summing across vectors
+    /// rather than into the products. In batches it's the fastest.
+    pub fn detect_single() -> Self {
+        match Self::detect() {
+            Kernel::Avx512 if Kernel::Avx2.is_supported() => Kernel::Avx2,
+            kernel => kernel,
+        }
+    }
+
+    /// Whether this CPU has the instructions the kernel needs.
+    pub fn is_supported(self) -> bool {
+        match self {
+            Kernel::Scalar => true,
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Sse2 => is_x86_feature_detected!("sse2"),
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2 => is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma"),
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx512 => is_x86_feature_detected!("avx512f"),
+            #[cfg(not(target_arch = "x86_64"))]
+            _ => false,
+        }
+    }
+}
+
+/// A `Kernel` the CPU is known to support, and so safe to run.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Supported(Kernel);
+
+impl Supported {
+    /// `None` if the CPU doesn't support `kernel`.
+    pub fn new(kernel: Kernel) -> Option<Self> {
+        if kernel.is_supported() {
+            Some(Supported(kernel))
+        } else {
+            None
+        }
+    }
+
+    pub fn detect() -> Self {
+        Supported(Kernel::detect())
+    }
+
+    pub fn detect_single() -> Self {
+        Supported(Kernel::detect_single())
+    }
+
+    pub fn kernel(self) -> Kernel {
+        self.0
+    }
+
+    /// Set `out[i]` to `x * weights[i] + bias[i]`, computed in `f64`.
+    ///
+    /// All three slices must have the same length.
+    pub fn first_layer(self, x: f64, weights: &[f32], bias: &[f32], out: &mut [f32]) {
+        assert!(weights.len() == out.len() && bias.len() == out.len());
+        match self.0 {
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Sse2 => unsafe { x86::first_layer_sse2(x, weights, bias, out) },
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2 => unsafe { x86::first_layer_avx2(x, weights, bias, out) },
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx512 => unsafe { x86::first_layer_avx512(x, weights, bias, out) },
+            _ => scalar::first_layer(x, weights, bias, out),
+        }
+    }
+
+    /// Set `out` to the product of `weights`, a matrix with `out.len()` rows
+    /// and `input.len()` columns stored by rows, and `input`, plus `bias`.
+    pub fn matrix_vector(self, weights: &[f32], bias: &[f32], input: &[f32], out: &mut [f32]) {
+        assert!(weights.len() == out.len() * input.len() && bias.len() == out.len());
+        match self.0 {
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Sse2 => unsafe { x86::matrix_vector_sse2(weights, bias, input, out) },
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2 => unsafe { x86::matrix_vector_avx2(weights, bias, input, out) },
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx512 => unsafe { x86::matrix_vector_avx512(weights, bias, input, out) },
+            _ => scalar::matrix_vector(weights, bias, input, out),
+        }
+    }
+
+    /// Set `out` to the product of `weights`, a matrix with `bias.len()` rows
+    /// stored by rows, and `input`, a matrix with `n` columns stored by rows,
+    /// plus `bias[r]` in each column of row `r`.
+    pub fn matrix_matrix(
+        self,
+        weights: &[f32],
+        bias: &[f32],
+        input: &[f32],
+        n: usize,
+        out: &mut [f32],
+    ) {
+        if n == 0 || bias.is_empty() {
+            return;
+        }
+        let depth = input.len() / n;
+        assert!(input.len() == depth * n && weights.len() == bias.len() * depth);
+        assert_eq!(out.len(), bias.len() * n);
+        match self.0 {
+            // the vector versions need at least one row of `input`
+            _ if depth == 0 => scalar::matrix_matrix(weights, bias, input, n, 0, out),
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Sse2 => unsafe { x86::matrix_matrix_sse2(weights, bias, input, n, out) },
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2 => unsafe { x86::matrix_matrix_avx2(weights, bias, input, n, out) },
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx512 => unsafe { x86::matrix_matrix_avx512(weights, bias, input, n, out) },
+            _ => scalar::matrix_matrix(weights, bias, input, n, 0, out),
+        }
+    }
+
+    /// The dot product of two slices of the same length.
+    pub fn dot(self, a: &[f32], b: &[f32]) -> f32 {
+        assert_eq!(a.len(), b.len());
+        match self.0 {
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Sse2 => unsafe { x86::dot_sse2(a, b) },
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2 => unsafe { x86::dot_avx2(a, b) },
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx512 => unsafe { x86::dot_avx512(a, b) },
+            _ => scalar::dot(a, b),
+        }
+    }
+
+    /// Multiply each negative value by `slope`.
+    pub fn leaky_relu(self, values: &mut [f32], slope: f32) {
+        match self.0 {
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Sse2 => unsafe { x86::leaky_relu_sse2(values, slope) },
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2 => unsafe { x86::leaky_relu_avx2(values, slope) },
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx512 => unsafe { x86::leaky_relu_avx512(values, slope) },
+            _ => scalar::leaky_relu(values, slope),
+        }
+    }
+
+    /// Replace each negative value with 0.
+    ///
+    /// This is not `leaky_relu` with a slope of 0, which would turn `-inf`
+    /// into NaN and negative values into `-0.0`.
+    pub fn relu(self, values: &mut [f32]) {
+        match self.0 {
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Sse2 => unsafe { x86::relu_sse2(values) },
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2 => unsafe { x86::relu_avx2(values) },
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx512 => unsafe { x86::relu_avx512(values) },
+            _ => scalar::relu(values),
+        }
+    }
+}
+
+mod scalar {
+    pub fn first_layer(x: f64, weights: &[f32], bias: &[f32], out: &mut [f32]) {
+        for i in 0..out.len() {
+            out[i] = (x * weights[i] as f64 + bias[i] as f64) as f32;
+        }
+    }
+
+    pub fn matrix_vector(weights: &[f32], bias: &[f32], input: &[f32], out: &mut [f32]) {
+        for row in 0..out.len() {
+            let start = row * input.len();
+            out[row] = dot(&weights[start..start + input.len()], input) + bias[row];
+        }
+    }
+
+    // Like `Supported::matrix_matrix`, but only for columns `first..n`.
+    pub fn matrix_matrix(
+        weights: &[f32],
+        bias: &[f32],
+        input: &[f32],
+        n: usize,
+        first: usize,
+        out: &mut [f32],
+    ) {
+        let depth = input.len() / n;
+        for (r, &b) in bias.iter().enumerate() {
+            let out = &mut out[r * n + first..(r + 1) * n];
+            for value in out.iter_mut() {
+                *value = b;
+            }
+            for k in 0..depth {
+                let w = weights[r * depth + k];
+                for (value, &x) in out.iter_mut().zip(input[k * n + first..(k + 1) * n].iter()) {
+                    *value += w * x;
+                }
+            }
+        }
+    }
+
+    pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+        let mut result = 0.0;
+        for i in 0..a.len() {
+            result += a[i] * b[i];
+        }
+        result
+    }
+
+    pub fn leaky_relu(values: &mut [f32], slope: f32) {
+        for value in values.iter_mut() {
+            if *value < 0.0 {
+                *value *= slope;
+            }
+        }
+    }
+
+    pub fn relu(values: &mut [f32]) {
+        for value in values.iter_mut() {
+            if *value < 0.0 {
+                *value = 0.0;
+            }
+        }
+    }
+}
+
+// Each function handles as many whole vectors as fit, and passes what's left
+// over to the scalar version, except that the AVX-512 dot product and
+// activation finish with a masked load instead.
+//
+// `first_layer` widens four (or eight) weights and biases at a time to `f64`,
+// does the arithmetic there, and narrows the results back, exactly as the
+// scalar version does one at a time. It uses no FMA, since that would round
+// differently.
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    use super::scalar;
+
+    // The number of whole vectors of `lanes` lanes in `len` items, times
+    // `lanes`.
+    fn whole(len: usize, lanes: usize) -> usize {
+        len - len % lanes
+    }
+
+    // A mask selecting the first `len % 16` lanes of a 512 bit vector.
+    fn tail_mask(len: usize) -> __mmask16 {
+        ((1u32 << (len % 16)) - 1) as __mmask16
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn sum_sse2(v: __m128) -> f32 {
+        let v = _mm_add_ps(v, _mm_movehl_ps(v, v));
+        _mm_cvtss_f32(_mm_add_ss(v, _mm_shuffle_ps(v, v, 1)))
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn sum_avx2(v: __m256) -> f32 {
+        sum_sse2(_mm_add_ps(
+            _mm256_castps256_ps128(v),
+            _mm256_extractf128_ps(v, 1),
+        ))
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn first_layer_sse2(x: f64, weights: &[f32], bias: &[f32], out: &mut [f32]) {
+        let n = whole(out.len(), 4);
+        let x4 = _mm_set1_pd(x);
+        for i in (0..n).step_by(4) {
+            let w = _mm_loadu_ps(weights.as_ptr().add(i));
+            let b = _mm_loadu_ps(bias.as_ptr().add(i));
+            let lo = _mm_add_pd(_mm_mul_pd(x4, _mm_cvtps_pd(w)), _mm_cvtps_pd(b));
+            let hi = _mm_add_pd(
+                _mm_mul_pd(x4, _mm_cvtps_pd(_mm_movehl_ps(w, w))),
+                _mm_cvtps_pd(_mm_movehl_ps(b, b)),
+            );
+            let result = _mm_movelh_ps(_mm_cvtpd_ps(lo), _mm_cvtpd_ps(hi));
+            _mm_storeu_ps(out.as_mut_ptr().add(i), result);
+        }
+        scalar::first_layer(x, &weights[n..], &bias[n..], &mut out[n..]);
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn first_layer_avx2(x: f64, weights: &[f32], bias: &[f32], out: &mut [f32]) {
+        let n = whole(out.len(), 4);
+        let x4 = _mm256_set1_pd(x);
+        for i in (0..n).step_by(4) {
+            let w = _mm256_cvtps_pd(_mm_loadu_ps(weights.as_ptr().add(i)));
+            let b = _mm256_cvtps_pd(_mm_loadu_ps(bias.as_ptr().add(i)));
+            let result = _mm256_cvtpd_ps(_mm256_add_pd(_mm256_mul_pd(x4, w), b));
+            _mm_storeu_ps(out.as_mut_ptr().add(i), result);
+        }
+        scalar::first_layer(x, &weights[n..], &bias[n..], &mut out[n..]);
+    }
+
+    #[target_feature(enable = "avx512f")]
+    pub unsafe fn first_layer_avx512(x: f64, weights: &[f32], bias: &[f32], out: &mut [f32]) {
+        let n = whole(out.len(), 8);
+        let x8 = _mm512_set1_pd(x);
+        for i in (0..n).step_by(8) {
+            let w = _mm512_cvtps_pd(_mm256_loadu_ps(weights.as_ptr().add(i)));
+            let b = _mm512_cvtps_pd(_mm256_loadu_ps(bias.as_ptr().add(i)));
+            let result = _mm512_cvtpd_ps(_mm512_add_pd(_mm512_mul_pd(x8, w), b));
+            _mm256_storeu_ps(out.as_mut_ptr().add(i), result);
+        }
+        scalar::first_layer(x, &weights[n..], &bias[n..], &mut out[n..]);
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn dot_sse2(a: &[f32], b: &[f32]) -> f32 {
+        let n = whole(a.len(), 4);
+        let mut acc = _mm_setzero_ps();
+        for i in (0..n).step_by(4) {
+            let product = _mm_mul_ps(
+                _mm_loadu_ps(a.as_ptr().add(i)),
+                _mm_loadu_ps(b.as_ptr().add(i)),
+            );
+            acc = _mm_add_ps(acc, product);
+        }
+        sum_sse2(acc) + scalar::dot(&a[n..], &b[n..])
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn dot_avx2(a: &[f32], b: &[f32]) -> f32 {
+        let n = whole(a.len(), 8);
+        let mut acc = _mm256_setzero_ps();
+        for i in (0..n).step_by(8) {
+            acc = _mm256_fmadd_ps(
+                _mm256_loadu_ps(a.as_ptr().add(i)),
+                _mm256_loadu_ps(b.as_ptr().add(i)),
+                acc,
+            );
+        }
+        sum_avx2(acc) + scalar::dot(&a[n..], &b[n..])
+    }
+
+    #[target_feature(enable = "avx512f")]
+    pub unsafe fn dot_avx512(a: &[f32], b: &[f32]) -> f32 {
+        let n = whole(a.len(), 16);
+        let mut acc = _mm512_setzero_ps();
+        for i in (0..n).step_by(16) {
+            acc = _mm512_fmadd_ps(
+                _mm512_loadu_ps(a.as_ptr().add(i)),
+                _mm512_loadu_ps(b.as_ptr().add(i)),
+                acc,
+            );
+        }
+        let mask = tail_mask(a.len());
+        acc = _mm512_fmadd_ps(
+            _mm512_maskz_loadu_ps(mask, a.as_ptr().add(n)),
+            _mm512_maskz_loadu_ps(mask, b.as_ptr().add(n)),
+            acc,
+        );
+        _mm512_reduce_add_ps(acc)
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn matrix_vector_sse2(
+        weights: &[f32],
+        bias: &[f32],
+        input: &[f32],
+        out: &mut [f32],
+    ) {
+        let columns = input.len();
+        for row in 0..out.len() {
+            out[row] = dot_sse2(&weights[row * columns..(row + 1) * columns], input) + bias[row];
+        }
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn matrix_vector_avx2(
+        weights: &[f32],
+        bias: &[f32],
+        input: &[f32],
+        out: &mut [f32],
+    ) {
+        let columns = input.len();
+        for row in 0..out.len() {
+            out[row] = dot_avx2(&weights[row * columns..(row + 1) * columns], input) + bias[row];
+        }
+    }
+
+    #[target_feature(enable = "avx512f")]
+    pub unsafe fn matrix_vector_avx512(
+        weights: &[f32],
+        bias: &[f32],
+        input: &[f32],
+        out: &mut [f32],
+    ) {
+        let columns = input.len();
+        for row in 0..out.len() {
+            out[row] = dot_avx512(&weights[row * columns..(row + 1) * columns], input) + bias[row];
+        }
+    }
+
+    // The number of rows in a tile of `matrix_matrix`.
+    const ROWS: usize = 4;
+
+    // Define a `matrix_matrix` function and the function computing its tiles,
+    // for vectors of `$lanes` floats.
+    macro_rules! matrix_matrix {
+        (
+            $name:ident,
+            $tile:ident,
+            $feature:tt,
+            $lanes:expr,
+            $zero:ident,
+            $set1:ident,
+            $load:ident,
+            $store:ident,
+            $add:ident,
+            $fmadd:ident
+        ) => {
+            #[target_feature(enable = $feature)]
+            pub unsafe fn $name(
+                weights: &[f32],
+                bias: &[f32],
+                input: &[f32],
+                n: usize,
+                out: &mut [f32],
+            ) {
+                let rows = bias.len();
+                let depth = input.len() / n;
+                let columns = whole(n, 2 * $lanes);
+                for j in (0..columns).step_by(2 * $lanes) {
+                    let mut r = 0;
+                    while r + ROWS <= rows {
+                        $tile::<ROWS>(
+                            &weights[r * depth..(r + ROWS) * depth],
+                            &bias[r..r + ROWS],
+                            &input[j..],
+                            n,
+                            &mut out[r * n + j..],
+                        );
+                        r += ROWS;
+                    }
+                    for r in r..rows {
+                        $tile::<1>(
+                            &weights[r * depth..(r + 1) * depth],
+                            &bias[r..r + 1],
+                            &input[j..],
+                            n,
+                            &mut out[r * n + j..],
+                        );
+                    }
+                }
+                scalar::matrix_matrix(weights, bias, input, n, columns, out);
+            }
+
+            // Compute the `M` by `2 * $lanes` tile of the output starting at
+            // `out[0]`, from the `M` rows of `weights` and the columns of
+            // `input` starting at `input[0]`.
+            #[target_feature(enable = $feature)]
+            #[inline]
+            unsafe fn $tile<const M: usize>(
+                weights: &[f32],
+                bias: &[f32],
+                input: &[f32],
+                n: usize,
+                out: &mut [f32],
+            ) {
+                let depth = weights.len() / M;
+                let w = weights.as_ptr();
+                let a = input.as_ptr();
+                let mut acc = [[$zero(); 2]; M];
+                for k in 0..depth {
+                    let a0 = $load(a.add(k * n));
+                    let a1 = $load(a.add(k * n + $lanes));
+                    for m in 0..M {
+                        let wm = $set1(*w.add(m * depth + k));
+                        acc[m][0] = $fmadd(wm, a0, acc[m][0]);
+                        acc[m][1] = $fmadd(wm, a1, acc[m][1]);
+                    }
+                }
+                for m in 0..M {
+                    let b = $set1(bias[m]);
+                    let o = out.as_mut_ptr().add(m * n);
+                    $store(o, $add(acc[m][0], b));
+                    $store(o.add($lanes), $add(acc[m][1], b));
+                }
+            }
+        };
+    }
+
+    #[target_feature(enable = "sse2")]
+    #[inline]
+    unsafe fn fmadd_sse2(a: __m128, b: __m128, c: __m128) -> __m128 {
+        _mm_add_ps(_mm_mul_ps(a, b), c)
+    }
+
+    matrix_matrix!(
+        matrix_matrix_sse2,
+        tile_sse2,
+        "sse2",
+        4,
+        _mm_setzero_ps,
+        _mm_set1_ps,
+        _mm_loadu_ps,
+        _mm_storeu_ps,
+        _mm_add_ps,
+        fmadd_sse2
+    );
+
+    matrix_matrix!(
+        matrix_matrix_avx2,
+        tile_avx2,
+        "avx2,fma",
+        8,
+        _mm256_setzero_ps,
+        _mm256_set1_ps,
+        _mm256_loadu_ps,
+        _mm256_storeu_ps,
+        _mm256_add_ps,
+        _mm256_fmadd_ps
+    );
+
+    matrix_matrix!(
+        matrix_matrix_avx512,
+        tile_avx512,
+        "avx512f",
+        16,
+        _mm512_setzero_ps,
+        _mm512_set1_ps,
+        _mm512_loadu_ps,
+        _mm512_storeu_ps,
+        _mm512_add_ps,
+        _mm512_fmadd_ps
+    );
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn leaky_relu_sse2(values: &mut [f32], slope: f32) {
+        let n = whole(values.len(), 4);
+        let slope4 = _mm_set1_ps(slope);
+        for i in (0..n).step_by(4) {
+            let p = values.as_mut_ptr().add(i);
+            let v = _mm_loadu_ps(p);
+            let negative = _mm_cmplt_ps(v, _mm_setzero_ps());
+            let scaled = _mm_mul_ps(v, slope4);
+            _mm_storeu_ps(
+                p,
+                _mm_or_ps(_mm_and_ps(negative, scaled), _mm_andnot_ps(negative, v)),
+            );
+        }
+        scalar::leaky_relu(&mut values[n..], slope);
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn leaky_relu_avx2(values: &mut [f32], slope: f32) {
+        let n = whole(values.len(), 8);
+        let slope8 = _mm256_set1_ps(slope);
+        for i in (0..n).step_by(8) {
+            let p = values.as_mut_ptr().add(i);
+            let v = _mm256_loadu_ps(p);
+            let negative = _mm256_cmp_ps(v, _mm256_setzero_ps(), _CMP_LT_OQ);
+            _mm256_storeu_ps(p, _mm256_blendv_ps(v, _mm256_mul_ps(v, slope8), negative));
+        }
+        scalar::leaky_relu(&mut values[n..], slope);
+    }
+
+    #[target_feature(enable = "avx512f")]
+    pub unsafe fn leaky_relu_avx512(values: &mut [f32], slope: f32) {
+        let slope16 = _mm512_set1_ps(slope);
+        let n = whole(values.len(), 16);
+        for i in (0..n).step_by(16) {
+            let p = values.as_mut_ptr().add(i);
+            let v = _mm512_loadu_ps(p);
+            let negative = _mm512_cmp_ps_mask(v, _mm512_setzero_ps(), _CMP_LT_OQ);
+            _mm512_storeu_ps(p, _mm512_mask_mul_ps(v, negative, v, slope16));
+        }
+        let mask = tail_mask(values.len());
+        let p = values.as_mut_ptr().add(n);
+        let v = _mm512_maskz_loadu_ps(mask, p);
+        let negative = _mm512_cmp_ps_mask(v, _mm512_setzero_ps(), _CMP_LT_OQ);
+        _mm512_mask_storeu_ps(p, mask, _mm512_mask_mul_ps(v, negative, v, slope16));
+    }
+
+    // `relu` keeps the values that aren't less than 0, including NaN and
+    // `-0.0`, and zeroes the rest, as the scalar version does.
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn relu_sse2(values: &mut [f32]) {
+        let n = whole(values.len(), 4);
+        for i in (0..n).step_by(4) {
+            let p = values.as_mut_ptr().add(i);
+            let v = _mm_loadu_ps(p);
+            let negative = _mm_cmplt_ps(v, _mm_setzero_ps());
+            _mm_storeu_ps(p, _mm_andnot_ps(negative, v));
+        }
+        scalar::relu(&mut values[n..]);
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn relu_avx2(values: &mut [f32]) {
+        let n = whole(values.len(), 8);
+        for i in (0..n).step_by(8) {
+            let p = values.as_mut_ptr().add(i);
+            let v = _mm256_loadu_ps(p);
+            let negative = _mm256_cmp_ps(v, _mm256_setzero_ps(), _CMP_LT_OQ);
+            _mm256_storeu_ps(p, _mm256_andnot_ps(negative, v));
+        }
+        scalar::relu(&mut values[n..]);
+    }
+
+    #[target_feature(enable = "avx512f")]
+    pub unsafe fn relu_avx512(values: &mut [f32]) {
+        let n = whole(values.len(), 16);
+        for i in (0..n).step_by(16) {
+            let p = values.as_mut_ptr().add(i);
+            let v = _mm512_loadu_ps(p);
+            let negative = _mm512_cmp_ps_mask(v, _mm512_setzero_ps(), _CMP_LT_OQ);
+            _mm512_storeu_ps(p, _mm512_mask_blend_ps(negative, v, _mm512_setzero_ps()));
+        }
+        let mask = tail_mask(values.len());
+        let p = values.as_mut_ptr().add(n);
+        let v = _mm512_maskz_loadu_ps(mask, p);
+        let negative = _mm512_cmp_ps_mask(v, _mm512_setzero_ps(), _CMP_LT_OQ);
+        _mm512_mask_storeu_ps(
+            p,
+            mask,
+            _mm512_mask_blend_ps(negative, v, _mm512_setzero_ps()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    #[test]
+    fn detect() {
+        assert!(Kernel::detect().is_supported());
+        assert!(Kernel::detect_single().is_supported());
+        if Kernel::Avx2.is_supported() {
+            assert_eq!(Kernel::detect_single(), Kernel::Avx2);
+        } else {
+            assert_eq!(Kernel::detect_single(), Kernel::detect());
+        }
+    }
+
+    fn random(rng: &mut XorShiftRng, len: usize) -> Vec<f32> {
+        (0..len).map(|_| rng.gen_range(-2.0, 2.0)).collect()
+    }
+
+    // Check that `results` differ from `expected` only by rounding.
+    fn check_close(kernel: Supported, results: &[f32], expected: &[f32]) {
+        assert_eq!(results.len(), expected.len());
+        for (&a, &b) in results.iter().zip(expected.iter()) {
+            assert!(
+                (a - b).abs() <= 1e-5 * (1.0 + b.abs()),
+                "{:?} {} {}",
+                kernel,
+                a,
+                b
+            );
+        }
+    }
+
+    #[test]
+    fn against_scalar() {
+        let mut rng = XorShiftRng::from_seed([18; 16]);
+        let scalar = Supported::new(Kernel::Scalar).unwrap();
+        assert!(Kernel::detect().is_supported());
+        for &kernel in Kernel::ALL.iter() {
+            let kernel = match Supported::new(kernel) {
+                Some(kernel) => kernel,
+                None => continue,
+            };
+            for len in 0..40 {
+                let x = rng.gen_range(-1e6, 1e6);
+                let weights = random(&mut rng, len);
+                let bias = random(&mut rng, len);
+                let mut expected = vec![0.0; len];
+                let mut out = vec![0.0; len];
+                scalar.first_layer(x, &weights, &bias, &mut expected);
+                kernel.first_layer(x, &weights, &bias, &mut out);
+                assert_eq!(out, expected, "{:?}", kernel);
+
+                let mut values = random(&mut rng, len);
+                let mut expected = values.clone();
+                scalar.leaky_relu(&mut expected, 0.3);
+                kernel.leaky_relu(&mut values, 0.3);
+                assert_eq!(values, expected, "{:?}", kernel);
+
+                // compare bits, so that NaN matches NaN and `-0.0` only
+                // matches `-0.0`
+                let special = [f32::NEG_INFINITY, f32::INFINITY, f32::NAN, -0.0, 0.0];
+                let mut values = random(&mut rng, len);
+                for (value, &x) in values.iter_mut().zip(special.iter().cycle()).step_by(3) {
+                    *value = x;
+                }
+                let mut expected = values.clone();
+                scalar.relu(&mut expected);
+                kernel.relu(&mut values);
+                let bits = |v: &[f32]| v.iter().map(|x| x.to_bits()).collect::<Vec<_>>();
+                assert_eq!(bits(&values), bits(&expected), "{:?}", kernel);
+                assert!(expected.iter().all(|&x| x.is_nan() || x >= 0.0));
+
+                let a = random(&mut rng, len);
+                let b = random(&mut rng, len);
+                check_close(kernel, &[kernel.dot(&a, &b)], &[scalar.dot(&a, &b)]);
+
+                for &rows in [1, 3, 16].iter() {
+                    let weights = random(&mut rng, rows * len);
+                    let bias = random(&mut rng, rows);
+                    let input = random(&mut rng, len);
+                    let mut expected = vec![0.0; rows];
+                    let mut out = vec![0.0; rows];
+                    scalar.matrix_vector(&weights, &bias, &input, &mut expected);
+                    kernel.matrix_vector(&weights, &bias, &input, &mut out);
+                    check_close(kernel, &out, &expected);
+
+                    // enough columns for a few tiles, with some left over
+                    for &n in [1, 8, 31, 64, 75].iter() {
+                        let input = random(&mut rng, len * n);
+                        let mut expected = vec![0.0; rows * n];
+                        let mut out = vec![0.0; rows * n];
+                        scalar.matrix_matrix(&weights, &bias, &input, n, &mut expected);
+                        kernel.matrix_matrix(&weights, &bias, &input, n, &mut out);
+                        check_close(kernel, &out, &expected);
+
+                        // the last column is a matrix-vector product
+                        let column: Vec<f32> = (0..len).map(|k| input[k * n + n - 1]).collect();
+                        let last: Vec<f32> = (0..rows).map(|r| out[r * n + n - 1]).collect();
+                        let mut expected = vec![0.0; rows];
+                        scalar.matrix_vector(&weights, &bias, &column, &mut expected);
+                        check_close(kernel, &last, &expected);
+                    }
+                }
+            }
+        }
+    }
+}
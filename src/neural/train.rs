@@ -0,0 +1,653 @@
+//! Training a `Network` by backpropagation, without Python.
+//!
+//! `train` fits a network's weights to a set of keys and labels with
+//! minibatch gradient descent, as `train.py` does with Keras. The parameters
+//! are copied out of the network into one flat array for training, so the
+//! optimizers can treat them all alike, and copied back at the end.
+//!
+//! The forward pass here is plain scalar code rather than the kernels, since
+//! the backward pass needs the value of every unit for every key anyway; the
+//! first layer is still computed in `f64`, as in `Network::apply_buffer`.
+
+use std::slice;
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use super::{Activation, Network, NetworkBuilder};
+
+/// The function of the network's predictions that training minimizes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Loss {
+    /// The mean of the squared errors.
+    MeanSquared,
+
+    /// The largest absolute error. Each step only moves the prediction that
+    /// is furthest off.
+    MaxAbsolute,
+}
+
+/// How to change the parameters given the gradient of the loss.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Optimizer {
+    /// Plain gradient descent.
+    Sgd { learning_rate: f32 },
+
+    /// Adam, as described by Kingma and Ba.
+    Adam {
+        learning_rate: f32,
+        beta1: f32,
+        beta2: f32,
+        epsilon: f32,
+    },
+}
+
+impl Optimizer {
+    /// Adam with the learning rate `learning_rate`, and Keras's defaults for
+    /// the other parameters.
+    pub fn adam(learning_rate: f32) -> Self {
+        Optimizer::Adam {
+            learning_rate,
+            beta1: 0.9,
+            beta2: 0.999,
+            epsilon: 1e-7,
+        }
+    }
+}
+
+/// The settings for `train`.
+///
+/// The default is what `train.py` uses: 64 epochs of 32 keys per batch,
+/// minimizing the mean squared error with Adam.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Config {
+    pub loss: Loss,
+    pub optimizer: Optimizer,
+    /// The number of passes over the data.
+    pub epochs: usize,
+    /// The number of keys in each step.
+    pub batch_size: usize,
+    /// Seeds the shuffling of the keys before each epoch.
+    pub seed: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            loss: Loss::MeanSquared,
+            optimizer: Optimizer::adam(0.001),
+            epochs: 64,
+            batch_size: 32,
+            seed: 0,
+        }
+    }
+}
+
+/// A network with hidden layers of the given widths, each followed by
+/// `activation`, and a linear output layer, ready to train.
+///
+/// The weights are drawn from the uniform distribution Keras uses for dense
+/// layers, seeded with `seed`, and the biases are 0.
+///
+/// Panics if `widths` is empty or any width is 0.
+pub fn initial_network(widths: &[usize], activation: Activation, seed: u64) -> Network {
+    assert!(!widths.is_empty(), "initial_network: no hidden layers");
+    assert!(!widths.contains(&0), "initial_network: a hidden layer has width 0");
+
+    let mut rng = XorShiftRng::seed_from_u64(seed);
+    let mut builder = NetworkBuilder::new();
+    let mut columns = 1;
+    for (i, &rows) in widths.iter().chain(Some(&1)).enumerate() {
+        let limit = (6.0 / (rows + columns) as f32).sqrt();
+        let weights: Vec<f32> = (0..rows * columns)
+            .map(|_| rng.gen_range(-limit, limit))
+            .collect();
+        let activation = if i == widths.len() {
+            Activation::Identity
+        } else {
+            activation
+        };
+        builder = builder.layer(rows, columns, &weights, &vec![0.0; rows], activation);
+        columns = rows;
+    }
+    builder
+        .build()
+        .expect("initial_network: the layers don't fit together")
+}
+
+/// Train `network` to predict `labels[i]` from `keys[i]`.
+///
+/// Returns the loss over all the keys after each epoch.
+///
+/// Panics if there are no keys, the slices have different lengths, or the
+/// batch size is 0.
+pub fn train(network: &mut Network, keys: &[f64], labels: &[f32], config: &Config) -> Vec<f32> {
+    assert!(!keys.is_empty(), "train: no keys");
+    assert_eq!(
+        keys.len(),
+        labels.len(),
+        "train: keys and labels have different lengths"
+    );
+    assert!(config.batch_size > 0, "train: the batch size is 0");
+
+    let (shapes, mut params) = flatten(network);
+    let mut work = Work::new(&shapes);
+    let mut grads = vec![0.0; params.len()];
+    let mut optimizer = OptimizerState::new(config.optimizer, params.len());
+    let mut rng = XorShiftRng::seed_from_u64(config.seed);
+    let mut order: Vec<usize> = (0..keys.len()).collect();
+    let mut batch_keys = Vec::with_capacity(config.batch_size);
+    let mut batch_labels = Vec::with_capacity(config.batch_size);
+    let mut history = Vec::with_capacity(config.epochs);
+
+    for _ in 0..config.epochs {
+        rng.shuffle(&mut order);
+        for batch in order.chunks(config.batch_size) {
+            batch_keys.clear();
+            batch_keys.extend(batch.iter().map(|&i| keys[i]));
+            batch_labels.clear();
+            batch_labels.extend(batch.iter().map(|&i| labels[i]));
+            gradient(
+                &shapes,
+                &params,
+                &batch_keys,
+                &batch_labels,
+                config.loss,
+                &mut grads,
+                &mut work,
+            );
+            optimizer.step(&mut params, &grads);
+        }
+        let predictions: Vec<f32> = keys
+            .iter()
+            .map(|&x| forward(&shapes, &params, x, &mut work))
+            .collect();
+        history.push(loss(config.loss, &predictions, labels, None));
+    }
+
+    for (layer, shape) in layers(network).into_iter().zip(shapes.iter()) {
+        layer.0.copy_from_slice(&params[shape.weights()]);
+        layer.1.copy_from_slice(&params[shape.bias()]);
+    }
+    history
+}
+
+// Where a layer's parameters are in the flat array: its weights by rows, then
+// its biases.
+#[derive(Clone, Copy, Debug)]
+struct Shape {
+    rows: usize,
+    columns: usize,
+    activation: Activation,
+    offset: usize,
+}
+
+impl Shape {
+    fn weights(&self) -> ::std::ops::Range<usize> {
+        self.offset..self.offset + self.rows * self.columns
+    }
+
+    fn bias(&self) -> ::std::ops::Range<usize> {
+        let start = self.offset + self.rows * self.columns;
+        start..start + self.rows
+    }
+}
+
+// The weights, biases and activation of each layer of `network`.
+fn layers(network: &mut Network) -> Vec<(&mut [f32], &mut [f32], Activation)> {
+    let mut layers = Vec::new();
+    let first = &mut network.first_layer;
+    layers.push((&mut *first.data, &mut *first.bias, first.activation));
+    for layer in network.interior_layers.iter_mut() {
+        layers.push((&mut *layer.data, &mut *layer.bias, layer.activation));
+    }
+    let last = &mut network.last_layer;
+    layers.push((
+        &mut *last.data,
+        slice::from_mut(&mut last.bias),
+        last.activation,
+    ));
+    layers
+}
+
+fn flatten(network: &mut Network) -> (Vec<Shape>, Vec<f32>) {
+    let mut shapes = Vec::new();
+    let mut params = Vec::new();
+    for (weights, bias, activation) in layers(network) {
+        shapes.push(Shape {
+            rows: bias.len(),
+            columns: weights.len() / bias.len(),
+            activation,
+            offset: params.len(),
+        });
+        params.extend_from_slice(weights);
+        params.extend_from_slice(bias);
+    }
+    (shapes, params)
+}
+
+impl Activation {
+    fn activate(self, z: f32) -> f32 {
+        use self::Activation::*;
+
+        match self {
+            Identity => z,
+            Relu if z < 0.0 => 0.0,
+            LeakyRelu(slope) if z < 0.0 => z * slope,
+            Relu | LeakyRelu(_) => z,
+            Tanh => z.tanh(),
+            Sigmoid => 1.0 / (1.0 + (-z).exp()),
+        }
+    }
+
+    // The derivative at `z`, where the activation is `y`.
+    fn derivative(self, z: f32, y: f32) -> f32 {
+        use self::Activation::*;
+
+        match self {
+            Identity => 1.0,
+            Relu if z < 0.0 => 0.0,
+            LeakyRelu(slope) if z < 0.0 => slope,
+            Relu | LeakyRelu(_) => 1.0,
+            Tanh => 1.0 - y * y,
+            Sigmoid => y * (1.0 - y),
+        }
+    }
+}
+
+// The values of each layer for one key, before and after the activation, and
+// space for the backward pass.
+struct Work {
+    z: Vec<Vec<f32>>,
+    y: Vec<Vec<f32>>,
+    delta: Vec<f32>,
+    next_delta: Vec<f32>,
+}
+
+impl Work {
+    fn new(shapes: &[Shape]) -> Self {
+        let values = || shapes.iter().map(|shape| vec![0.0; shape.rows]).collect();
+        Work {
+            z: values(),
+            y: values(),
+            delta: Vec::new(),
+            next_delta: Vec::new(),
+        }
+    }
+}
+
+// Run the network on `x`, leaving the value of each layer in `work`.
+fn forward(shapes: &[Shape], params: &[f32], x: f64, work: &mut Work) -> f32 {
+    for (l, shape) in shapes.iter().enumerate() {
+        let weights = &params[shape.weights()];
+        let bias = &params[shape.bias()];
+        let (before, after) = work.y.split_at_mut(l);
+        let z = &mut work.z[l];
+        for r in 0..shape.rows {
+            z[r] = if l == 0 {
+                (x * weights[r] as f64 + bias[r] as f64) as f32
+            } else {
+                let row = &weights[r * shape.columns..(r + 1) * shape.columns];
+                let input = &before[l - 1];
+                bias[r]
+                    + row
+                        .iter()
+                        .zip(input.iter())
+                        .map(|(w, a)| w * a)
+                        .sum::<f32>()
+            };
+            after[0][r] = shape.activation.activate(z[r]);
+        }
+    }
+    work.y[shapes.len() - 1][0]
+}
+
+// Add the gradient of `scale` times the network's prediction for `x`, as left
+// in `work` by `forward`, to `grads`.
+fn backward(
+    shapes: &[Shape],
+    params: &[f32],
+    x: f64,
+    scale: f32,
+    grads: &mut [f32],
+    work: &mut Work,
+) {
+    let last = shapes.len() - 1;
+    work.delta.clear();
+    let (z, y) = (work.z[last][0], work.y[last][0]);
+    work.delta
+        .push(scale * shapes[last].activation.derivative(z, y));
+
+    for (l, shape) in shapes.iter().enumerate().rev() {
+        let weights = &params[shape.weights()];
+        let grads = &mut grads[shape.offset..shape.offset + shape.rows * (shape.columns + 1)];
+        let (grad_weights, grad_bias) = grads.split_at_mut(shape.rows * shape.columns);
+        for (g, &d) in grad_bias.iter_mut().zip(work.delta.iter()) {
+            *g += d;
+        }
+
+        if l == 0 {
+            for (g, &d) in grad_weights.iter_mut().zip(work.delta.iter()) {
+                *g += (x * d as f64) as f32;
+            }
+            break;
+        }
+
+        // the weight gradients, and the deltas of the layer before
+        let input = &work.y[l - 1];
+        work.next_delta.clear();
+        work.next_delta.resize(shape.columns, 0.0);
+        for (r, &d) in work.delta.iter().enumerate() {
+            let row = r * shape.columns..(r + 1) * shape.columns;
+            for ((g, &w), (&a, next)) in grad_weights[row.clone()]
+                .iter_mut()
+                .zip(weights[row].iter())
+                .zip(input.iter().zip(work.next_delta.iter_mut()))
+            {
+                *g += d * a;
+                *next += d * w;
+            }
+        }
+        let activation = shapes[l - 1].activation;
+        for (c, next) in work.next_delta.iter_mut().enumerate() {
+            *next *= activation.derivative(work.z[l - 1][c], input[c]);
+        }
+        ::std::mem::swap(&mut work.delta, &mut work.next_delta);
+    }
+}
+
+// The loss of `predictions`. If `scales` is given, set each entry to the
+// derivative of the loss with respect to the corresponding prediction.
+fn loss(loss: Loss, predictions: &[f32], labels: &[f32], scales: Option<&mut Vec<f32>>) -> f32 {
+    let errors = predictions.iter().zip(labels.iter()).map(|(p, t)| p - t);
+    let n = predictions.len() as f32;
+    match loss {
+        Loss::MeanSquared => {
+            if let Some(scales) = scales {
+                scales.clear();
+                scales.extend(errors.clone().map(|e| 2.0 * e / n));
+            }
+            errors.map(|e| e * e).sum::<f32>() / n
+        }
+        Loss::MaxAbsolute => {
+            let (worst, error) = errors.enumerate().fold((0, 0.0f32), |(i, e), (j, f)| {
+                if f.abs() > e.abs() {
+                    (j, f)
+                } else {
+                    (i, e)
+                }
+            });
+            if let Some(scales) = scales {
+                scales.clear();
+                scales.resize(predictions.len(), 0.0);
+                if error != 0.0 {
+                    scales[worst] = error.signum();
+                }
+            }
+            error.abs()
+        }
+    }
+}
+
+// Set `grads` to the gradient of the loss over one batch, and return the loss.
+fn gradient(
+    shapes: &[Shape],
+    params: &[f32],
+    keys: &[f64],
+    labels: &[f32],
+    loss_fn: Loss,
+    grads: &mut [f32],
+    work: &mut Work,
+) -> f32 {
+    let predictions: Vec<f32> = keys
+        .iter()
+        .map(|&x| forward(shapes, params, x, work))
+        .collect();
+    let mut scales = Vec::new();
+    let value = loss(loss_fn, &predictions, labels, Some(&mut scales));
+
+    for g in grads.iter_mut() {
+        *g = 0.0;
+    }
+    for (&x, &scale) in keys.iter().zip(scales.iter()) {
+        if scale != 0.0 {
+            forward(shapes, params, x, work);
+            backward(shapes, params, x, scale, grads, work);
+        }
+    }
+    value
+}
+
+enum OptimizerState {
+    Sgd {
+        learning_rate: f32,
+    },
+    Adam {
+        learning_rate: f32,
+        beta1: f32,
+        beta2: f32,
+        epsilon: f32,
+        // the number of steps taken, and the moving averages of the gradient
+        // and its square
+        t: i32,
+        m: Vec<f32>,
+        v: Vec<f32>,
+    },
+}
+
+impl OptimizerState {
+    fn new(optimizer: Optimizer, len: usize) -> Self {
+        match optimizer {
+            Optimizer::Sgd { learning_rate } => OptimizerState::Sgd { learning_rate },
+            Optimizer::Adam {
+                learning_rate,
+                beta1,
+                beta2,
+                epsilon,
+            } => OptimizerState::Adam {
+                learning_rate,
+                beta1,
+                beta2,
+                epsilon,
+                t: 0,
+                m: vec![0.0; len],
+                v: vec![0.0; len],
+            },
+        }
+    }
+
+    fn step(&mut self, params: &mut [f32], grads: &[f32]) {
+        match self {
+            OptimizerState::Sgd { learning_rate } => {
+                for (p, &g) in params.iter_mut().zip(grads.iter()) {
+                    *p -= *learning_rate * g;
+                }
+            }
+            OptimizerState::Adam {
+                learning_rate,
+                beta1,
+                beta2,
+                epsilon,
+                t,
+                m,
+                v,
+            } => {
+                *t += 1;
+                let rate = *learning_rate * (1.0 - beta2.powi(*t)).sqrt() / (1.0 - beta1.powi(*t));
+                for i in 0..params.len() {
+                    let g = grads[i];
+                    m[i] = *beta1 * m[i] + (1.0 - *beta1) * g;
+                    v[i] = *beta2 * v[i] + (1.0 - *beta2) * g * g;
+                    params[i] -= rate * m[i] / (v[i].sqrt() + *epsilon);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use neural::Scratch;
+
+    fn network() -> Network {
+        let toml = "layer0 = [[0.5, -1.0, 2.0], [0.1, 0.2, -0.3]]\n\
+                    layer1 = [[0.5, -0.5, 1.0, 0.0, 2.0, -1.0], [0.5, -1.0]]\n\
+                    layer2 = [[1.5, -1.0], [0.5]]\n\
+                    activation0 = \"tanh\"\n\
+                    activation1 = \"sigmoid\"\n";
+        Network::from_toml(&toml.parse().unwrap())
+    }
+
+    #[test]
+    fn forward_matches_network() {
+        let mut network = network();
+        network.first_layer.activation = Activation::LeakyRelu(0.3);
+        let (shapes, params) = flatten(&mut network);
+        let mut work = Work::new(&shapes);
+        let mut buf1 = vec![0.0; network.buf_size()];
+        let mut buf2 = vec![0.0; network.buf_size()];
+        for &x in [-3.0, -0.5, 0.0, 0.25, 1.0, 4.0].iter() {
+            let expected = network.apply_buffer(x, &mut buf1, &mut buf2);
+            let result = forward(&shapes, &params, x, &mut work);
+            assert!((result - expected).abs() < 1e-5, "{} {}", result, expected);
+        }
+    }
+
+    #[test]
+    fn gradient_matches_differences() {
+        // the activations are smooth, so the gradient is close to the
+        // difference quotients
+        let (shapes, params) = flatten(&mut network());
+        let mut work = Work::new(&shapes);
+        let keys = [-1.5, -0.2, 0.3, 0.9, 2.0];
+        let labels = [0.0, 1.0, 0.5, 2.0, 1.5];
+        for &loss_fn in [Loss::MeanSquared, Loss::MaxAbsolute].iter() {
+            let mut grads = vec![0.0; params.len()];
+            gradient(
+                &shapes, &params, &keys, &labels, loss_fn, &mut grads, &mut work,
+            );
+            for i in 0..params.len() {
+                let value = |h: f32| {
+                    let mut params = params.clone();
+                    params[i] += h;
+                    let predictions: Vec<f32> = keys
+                        .iter()
+                        .map(|&x| forward(&shapes, &params, x, &mut Work::new(&shapes)))
+                        .collect();
+                    loss(loss_fn, &predictions, &labels, None)
+                };
+                let h = 1e-2;
+                let difference = (value(h) - value(-h)) / (2.0 * h);
+                assert!(
+                    (grads[i] - difference).abs() < 1e-2 * (1.0 + difference.abs()),
+                    "{:?} parameter {}: {} {}",
+                    loss_fn,
+                    i,
+                    grads[i],
+                    difference
+                );
+            }
+        }
+    }
+
+    // keys from 0 to 1, labelled with their index
+    fn data() -> (Vec<f64>, Vec<f32>) {
+        let keys: Vec<f64> = (0..256).map(|i| (i as f64 / 256.0).powi(2)).collect();
+        let labels: Vec<f32> = (0..256).map(|i| i as f32).collect();
+        (keys, labels)
+    }
+
+    #[test]
+    fn learns() {
+        let (keys, labels) = data();
+        let configs = [
+            Config {
+                optimizer: Optimizer::adam(0.05),
+                epochs: 100,
+                ..Default::default()
+            },
+            Config {
+                optimizer: Optimizer::Sgd {
+                    learning_rate: 1e-4,
+                },
+                epochs: 100,
+                ..Default::default()
+            },
+            Config {
+                loss: Loss::MaxAbsolute,
+                optimizer: Optimizer::adam(0.1),
+                epochs: 300,
+                batch_size: 256,
+                ..Default::default()
+            },
+        ];
+        for config in configs.iter() {
+            let mut network = initial_network(&[16, 16], Activation::LeakyRelu(0.3), 1);
+            let history = train(&mut network, &keys, &labels, config);
+            assert_eq!(history.len(), config.epochs);
+            let first = history[0];
+            let last = *history.last().unwrap();
+            assert!(last < first / 4.0, "{:?}: {} to {}", config, first, last);
+
+            // the history is the loss of the trained network
+            let mut out = vec![0.0; keys.len()];
+            network.apply_batch(&keys, &mut out, &mut Scratch::new());
+            let result = loss(config.loss, &out, &labels, None);
+            assert!((result - last).abs() <= 1e-3 * last, "{} {}", result, last);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "train: no keys")]
+    fn no_keys() {
+        let mut network = initial_network(&[4], Activation::Relu, 0);
+        train(&mut network, &[], &[], &Config::default());
+    }
+
+    #[test]
+    #[should_panic(expected = "width 0")]
+    fn zero_width() {
+        initial_network(&[4, 0, 4], Activation::Relu, 0);
+    }
+
+    #[test]
+    fn seeded() {
+        let (keys, labels) = data();
+        let config = Config {
+            epochs: 3,
+            batch_size: 10,
+            seed: 7,
+            ..Default::default()
+        };
+        let run = |seed| {
+            let mut network = initial_network(&[8, 8], Activation::Relu, seed);
+            train(&mut network, &keys, &labels, &config);
+            network
+        };
+        assert_eq!(run(1), run(1));
+        assert!(run(1) != run(2));
+        let mut network = run(1);
+        let mut other = Config { seed: 8, ..config };
+        assert!(
+            train(&mut network.clone(), &keys, &labels, &config)
+                != train(&mut network, &keys, &labels, &other)
+        );
+        other.epochs = 0;
+        assert_eq!(train(&mut network, &keys, &labels, &other), []);
+    }
+
+    #[test]
+    fn save_one_hidden_layer() {
+        let (keys, labels) = data();
+        let config = Config {
+            epochs: 3,
+            ..Default::default()
+        };
+        let mut network = initial_network(&[8], Activation::Relu, 3);
+        train(&mut network, &keys, &labels, &config);
+        let text = ::toml::to_string(&network.to_toml()).unwrap();
+        let loaded = Network::from_toml(&text.parse().unwrap());
+        assert_eq!(loaded, network);
+    }
+}
@@ -0,0 +1,299 @@
+//! Strategies for searching the keys of a single B Tree node.
+//!
+//! A search through a B Tree spends most of its time finding its place among
+//! the keys of each node it visits. `BTree` is generic over how that is done:
+//! each strategy here implements `NodeSearch`, and can be passed to methods
+//! like `BTree::search_with`.
+//!
+//! - `Linear` scans the keys in order, stopping at the first key not less than
+//!   the one searched for.
+//! - `Binary` is a binary search written so that the compiler can use
+//!   conditional moves instead of branches.
+//! - `Simd` compares against several keys at once using SSE2 or AVX2,
+//!   whichever the CPU supports, and is only available for `f32`, `u32` and
+//!   `u64` keys. On other architectures it falls back to `Binary`.
+//!
+//! All of them compare keys in the total order of `Key`.
+
+#[cfg(target_arch = "x86_64")]
+use std::sync::OnceLock;
+
+use key::Key;
+
+/// A way of finding a key's position among the sorted keys of a node.
+pub trait NodeSearch<K> {
+    /// The number of keys in `keys`, which must be sorted, that come before
+    /// `key`.
+    fn rank(keys: &[K], key: K) -> usize;
+}
+
+/// Scan the keys from the start.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct Linear;
+
+/// Branchless binary search.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct Binary;
+
+/// Count the keys less than the search key with SIMD comparisons, using the
+/// widest instructions the CPU supports.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct Simd;
+
+impl<K> NodeSearch<K> for Linear
+where
+    K: Key,
+{
+    fn rank(keys: &[K], key: K) -> usize {
+        let mut i = 0;
+        while i < keys.len() && keys[i].key_lt(&key) {
+            i += 1;
+        }
+        i
+    }
+}
+
+impl<K> NodeSearch<K> for Binary
+where
+    K: Key,
+{
+    fn rank(keys: &[K], key: K) -> usize {
+        if keys.is_empty() {
+            return 0;
+        }
+        // the answer is always in `base..base + len + 1`
+        let mut base = 0;
+        let mut len = keys.len();
+        while len > 1 {
+            let half = len / 2;
+            base = if keys[base + half - 1].key_lt(&key) {
+                base + half
+            } else {
+                base
+            };
+            len -= half;
+        }
+        base + keys[base].key_lt(&key) as usize
+    }
+}
+
+// Whether the CPU supports AVX2. `rank` runs once per node visited, so this is
+// detected on the first call and cached.
+#[cfg(target_arch = "x86_64")]
+fn has_avx2() -> bool {
+    static AVX2: OnceLock<bool> = OnceLock::new();
+    *AVX2.get_or_init(|| is_x86_feature_detected!("avx2"))
+}
+
+impl NodeSearch<f32> for Simd {
+    fn rank(keys: &[f32], key: f32) -> usize {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if has_avx2() {
+                return unsafe { x86::rank_f32_avx2(keys, key) };
+            }
+            unsafe { x86::rank_f32_sse2(keys, key) }
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            Binary::rank(keys, key)
+        }
+    }
+}
+
+impl NodeSearch<u32> for Simd {
+    fn rank(keys: &[u32], key: u32) -> usize {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if has_avx2() {
+                return unsafe { x86::rank_u32_avx2(keys, key) };
+            }
+            unsafe { x86::rank_u32_sse2(keys, key) }
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            Binary::rank(keys, key)
+        }
+    }
+}
+
+impl NodeSearch<u64> for Simd {
+    fn rank(keys: &[u64], key: u64) -> usize {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if has_avx2() {
+                return unsafe { x86::rank_u64_avx2(keys, key) };
+            }
+        }
+        Binary::rank(keys, key)
+    }
+}
+
+// Each function counts the keys less than `key`: whole vectors of keys are
+// compared at once and the comparison masks popcounted, and any keys left over
+// are compared one at a time. The integer versions flip the sign bit of both
+// sides, since SSE2 and AVX2 only have signed integer comparisons. The float
+// versions follow the total order of `Key` rather than IEEE comparisons: like
+// `f32::total_cmp`, they flip all but the sign bit of negative floats, after
+// which comparing the bits as signed integers gives the total order.
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    use key::Key;
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn total_order_sse2(v: __m128i) -> __m128i {
+        _mm_xor_si128(v, _mm_srli_epi32(_mm_srai_epi32(v, 31), 1))
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn total_order_avx2(v: __m256i) -> __m256i {
+        _mm256_xor_si256(v, _mm256_srli_epi32(_mm256_srai_epi32(v, 31), 1))
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn rank_f32_sse2(keys: &[f32], key: f32) -> usize {
+        let needle = total_order_sse2(_mm_set1_epi32(key.to_bits() as i32));
+        let mut count = 0;
+        let mut chunks = keys.chunks_exact(4);
+        for chunk in &mut chunks {
+            let v = total_order_sse2(_mm_loadu_si128(chunk.as_ptr() as *const __m128i));
+            let mask = _mm_movemask_ps(_mm_castsi128_ps(_mm_cmplt_epi32(v, needle)));
+            count += mask.count_ones() as usize;
+        }
+        count + chunks.remainder().iter().filter(|k| k.key_lt(&key)).count()
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn rank_f32_avx2(keys: &[f32], key: f32) -> usize {
+        let needle = total_order_avx2(_mm256_set1_epi32(key.to_bits() as i32));
+        let mut count = 0;
+        let mut chunks = keys.chunks_exact(8);
+        for chunk in &mut chunks {
+            let v = total_order_avx2(_mm256_loadu_si256(chunk.as_ptr() as *const __m256i));
+            let mask = _mm256_movemask_ps(_mm256_castsi256_ps(_mm256_cmpgt_epi32(needle, v)));
+            count += mask.count_ones() as usize;
+        }
+        count + chunks.remainder().iter().filter(|k| k.key_lt(&key)).count()
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn rank_u32_sse2(keys: &[u32], key: u32) -> usize {
+        let flip = _mm_set1_epi32(i32::MIN);
+        let needle = _mm_xor_si128(_mm_set1_epi32(key as i32), flip);
+        let mut count = 0;
+        let mut chunks = keys.chunks_exact(4);
+        for chunk in &mut chunks {
+            let v = _mm_xor_si128(_mm_loadu_si128(chunk.as_ptr() as *const __m128i), flip);
+            let mask = _mm_movemask_ps(_mm_castsi128_ps(_mm_cmplt_epi32(v, needle)));
+            count += mask.count_ones() as usize;
+        }
+        count + chunks.remainder().iter().filter(|k| k.key_lt(&key)).count()
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn rank_u32_avx2(keys: &[u32], key: u32) -> usize {
+        let flip = _mm256_set1_epi32(i32::MIN);
+        let needle = _mm256_xor_si256(_mm256_set1_epi32(key as i32), flip);
+        let mut count = 0;
+        let mut chunks = keys.chunks_exact(8);
+        for chunk in &mut chunks {
+            let v = _mm256_xor_si256(_mm256_loadu_si256(chunk.as_ptr() as *const __m256i), flip);
+            let mask = _mm256_movemask_ps(_mm256_castsi256_ps(_mm256_cmpgt_epi32(needle, v)));
+            count += mask.count_ones() as usize;
+        }
+        count + chunks.remainder().iter().filter(|k| k.key_lt(&key)).count()
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn rank_u64_avx2(keys: &[u64], key: u64) -> usize {
+        let flip = _mm256_set1_epi64x(i64::MIN);
+        let needle = _mm256_xor_si256(_mm256_set1_epi64x(key as i64), flip);
+        let mut count = 0;
+        let mut chunks = keys.chunks_exact(4);
+        for chunk in &mut chunks {
+            let v = _mm256_xor_si256(_mm256_loadu_si256(chunk.as_ptr() as *const __m256i), flip);
+            let mask = _mm256_movemask_pd(_mm256_castsi256_pd(_mm256_cmpgt_epi64(needle, v)));
+            count += mask.count_ones() as usize;
+        }
+        count + chunks.remainder().iter().filter(|k| k.key_lt(&key)).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    fn check<K, F>(mut gen: F)
+    where
+        K: Key + ::std::fmt::Debug,
+        F: FnMut() -> K,
+        Simd: NodeSearch<K>,
+    {
+        for len in 0..40 {
+            for _ in 0..50 {
+                let mut keys: Vec<K> = (0..len).map(|_| gen()).collect();
+                keys.sort_by(Key::key_cmp);
+                let mut needles: Vec<K> = (0..10).map(|_| gen()).collect();
+                needles.extend(keys.iter().cloned());
+                for &key in needles.iter() {
+                    let expected = keys.iter().filter(|k| k.key_lt(&key)).count();
+                    assert_eq!(Linear::rank(&keys, key), expected, "{:?} {:?}", keys, key);
+                    assert_eq!(Binary::rank(&keys, key), expected, "{:?} {:?}", keys, key);
+                    assert_eq!(Simd::rank(&keys, key), expected, "{:?} {:?}", keys, key);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn f32_keys() {
+        let mut rng = XorShiftRng::from_seed([1; 16]);
+        // including the values that IEEE comparisons get wrong
+        let special = [
+            -0.0,
+            0.0,
+            f32::NAN,
+            -f32::NAN,
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+        ];
+        check(|| {
+            if rng.gen_range(0, 8) == 0 {
+                special[rng.gen_range(0, special.len())]
+            } else {
+                rng.gen_range(-20, 20) as f32 / 4.0
+            }
+        });
+    }
+
+    #[test]
+    fn u32_keys() {
+        let mut rng = XorShiftRng::from_seed([2; 16]);
+        // small values exercise duplicates, large ones the sign bit
+        check(|| {
+            let x: u32 = rng.gen_range(0, 16);
+            if rng.gen::<bool>() {
+                x
+            } else {
+                u32::MAX - x
+            }
+        });
+    }
+
+    #[test]
+    fn u64_keys() {
+        let mut rng = XorShiftRng::from_seed([3; 16]);
+        check(|| {
+            let x: u64 = rng.gen_range(0, 16);
+            if rng.gen::<bool>() {
+                x
+            } else {
+                u64::MAX - x
+            }
+        });
+    }
+}
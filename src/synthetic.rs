@@ -3,15 +3,24 @@
 use rand::distributions::{Distribution, LogNormal};
 use rand::{FromEntropy, XorShiftRng};
 
+use key::Key;
+
+/// Call `f` `count` times and return the results, sorted.
+///
+/// Each number is passed through `Key::canonicalize`, so `-0.0` becomes
+/// `0.0`. Panics if `f` returns NaN.
 pub fn gen_numbers<F>(mut f: F, count: usize) -> Box<[f32]>
 where
     F: FnMut() -> f32,
 {
     let mut result = Vec::with_capacity(count);
     for _ in 0..count {
-        result.push(f());
+        let x = f()
+            .canonicalize()
+            .expect("gen_numbers: generated NaN, which can't be used as a key");
+        result.push(x);
     }
-    result.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    result.sort_by(Key::key_cmp);
     result.into_boxed_slice()
 }
 
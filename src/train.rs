@@ -1,6 +1,9 @@
 //! Run the Python script `train.py` to train a hierarchy of models.
+//!
+//! To train a single `Network` without Python, see `neural::train`.
 
 use std::ffi::OsStr;
+use std::fmt::Display;
 use std::fs::File;
 use std::io::Write;
 use std::path::Path;
@@ -8,25 +11,29 @@ use std::process::{Command, Stdio};
 
 use tempfile::NamedTempFile;
 
-pub fn train<P>(data: &[f32], layers: usize, width: usize, threshold: usize, py_path: &P)
+pub fn train<K, P>(data: &[K], layers: usize, width: usize, threshold: usize, py_path: &P)
 where
+    K: Display,
     P: AsRef<Path>,
 {
     train0(data, layers, width, threshold, py_path.as_ref());
 }
 
-fn train0(data: &[f32], layers: usize, width: usize, threshold: usize, py_path: &Path) {
+fn train0<K>(data: &[K], layers: usize, width: usize, threshold: usize, py_path: &Path)
+where
+    K: Display,
+{
     let os: &OsStr = py_path.as_ref();
     let file_name = NamedTempFile::new().expect("Unable to create temp file");
     {
         let mut file = File::create(&file_name).expect("Unable to open temp file");
-        for &datum in data.iter() {
+        for datum in data.iter() {
             writeln!(file, "{}", datum).expect("Unable to write to temp file");
         }
     }
     Command::new("python3.6")
         .arg(os)
-        .args(&[
+        .args([
             "--layers",
             &format!("{}", layers),
             "--width",